@@ -0,0 +1,80 @@
+//! Compares download throughput over loopback for different chunk sizes.
+//!
+//! Run with `cargo bench --bench chunk_size`.
+
+use std::{fs, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use landoh::app::{Config, Directory, Identity, KnownPeers, Limiter, Server};
+use landoh::client::{ChunkSize, Client, DownloadOptions};
+use tokio::sync::Mutex;
+
+/// Several files, so the adaptive mode gets to adjust between them.
+const FILES: usize = 16;
+const FILE_SIZE: usize = 8 * 1024 * 1024;
+const RUNS: usize = 3;
+
+#[tokio::main]
+async fn main() {
+    let base = std::env::temp_dir().join(format!("landoh-bench-{}", std::process::id()));
+    let share = base.join("bench");
+    fs::create_dir_all(&share).unwrap();
+    let data: Vec<u8> = (0..FILE_SIZE).map(|i| (i % 251) as u8).collect();
+    for i in 0..FILES {
+        fs::write(share.join(format!("blob-{}", i)), &data).unwrap();
+    }
+
+    let addr: SocketAddr = "127.0.0.1:19410".parse().unwrap();
+    let mut config = Config::new(vec![], "bench".to_string(), addr, None).unwrap();
+    config.shared_directories.push(Directory {
+        name: "bench".to_string(),
+        paths: vec![share.to_string_lossy().to_string()],
+    });
+    let server = Server::new(
+        Arc::new(Mutex::new(config)),
+        Arc::new(Limiter::default()),
+        Identity::generate().unwrap(),
+        Arc::new(KnownPeers::default()),
+    );
+    tokio::spawn(async move { server.serve(addr).await.unwrap() });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let url = format!("https://{}", addr);
+    let modes = vec![
+        ChunkSize::Fixed(4 * 1024),
+        ChunkSize::Fixed(16 * 1024),
+        ChunkSize::Fixed(64 * 1024),
+        ChunkSize::Fixed(256 * 1024),
+        ChunkSize::Fixed(1024 * 1024),
+        ChunkSize::Adaptive,
+    ];
+
+    println!("{:>16} {:>12}", "chunk size", "MiB/s");
+    for mode in modes {
+        let mut best: f64 = 0.0;
+        for run in 0..RUNS {
+            let dest = base.join(format!("dest-{}", run));
+            let _ = fs::remove_dir_all(&dest);
+            let client = Arc::new(Client::new(dest.to_string_lossy().to_string()));
+            let files = client
+                .get_directory("bench".to_string(), url.clone())
+                .await
+                .unwrap();
+
+            let options = DownloadOptions {
+                chunk_size: mode.clone(),
+                ..DownloadOptions::default()
+            };
+            let report = client.download(url.clone(), files, options).await.unwrap();
+            assert!(report.failed.is_empty(), "{:?}", report.failed);
+            best = best.max(report.throughput());
+        }
+
+        let label = match mode {
+            ChunkSize::Fixed(s) => format!("{} KiB", s / 1024),
+            other => format!("{:?}", other),
+        };
+        println!("{:>16} {:>12.1}", label, best / (1024.0 * 1024.0));
+    }
+
+    let _ = fs::remove_dir_all(PathBuf::from(&base));
+}
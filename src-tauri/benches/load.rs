@@ -0,0 +1,138 @@
+//! Serves many concurrent GetFile streams over loopback and measures the
+//! aggregate throughput and how responsive ListDirectories stays meanwhile.
+//!
+//! Run with `cargo bench --bench load`.
+
+use std::{
+    fs,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use landoh::app::{Config, Directory, Identity, KnownPeers, Limiter, Server};
+use landoh::client::{Client, DownloadOptions};
+use tokio::{sync::Mutex, task::JoinSet};
+
+const CLIENTS: usize = 8;
+const STREAMS_PER_CLIENT: usize = 8;
+const FILE_SIZE: usize = 8 * 1024 * 1024;
+
+#[tokio::main]
+async fn main() {
+    let base = std::env::temp_dir().join(format!("landoh-load-{}", std::process::id()));
+    let share = base.join("load");
+    fs::create_dir_all(&share).unwrap();
+    let data: Vec<u8> = (0..FILE_SIZE).map(|i| (i % 251) as u8).collect();
+    for i in 0..STREAMS_PER_CLIENT {
+        fs::write(share.join(format!("blob-{}", i)), &data).unwrap();
+    }
+
+    let addr: SocketAddr = "127.0.0.1:19411".parse().unwrap();
+    let mut config = Config::new(vec![], "load".to_string(), addr, None).unwrap();
+    config.shared_directories.push(Directory {
+        name: "load".to_string(),
+        paths: vec![share.to_string_lossy().to_string()],
+    });
+    let server = Server::new(
+        Arc::new(Mutex::new(config)),
+        Arc::new(Limiter::default()),
+        Identity::generate().unwrap(),
+        Arc::new(KnownPeers::default()),
+    );
+    tokio::spawn(async move { server.serve(addr).await.unwrap() });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let url = format!("https://{}", addr);
+    let probe = Client::new(base.to_string_lossy().to_string());
+    // Hashes the shared files once, so the streams below use the cache.
+    let files = probe
+        .get_directory("load".to_string(), url.clone())
+        .await
+        .unwrap();
+
+    let idle = list_latencies(&probe, &url, 50).await;
+
+    let start = Instant::now();
+    let mut downloads = JoinSet::new();
+    for i in 0..CLIENTS {
+        let client = Arc::new(Client::new(
+            base.join(format!("dest-{}", i)).to_string_lossy().to_string(),
+        ));
+        let url = url.clone();
+        let files = files.clone();
+        downloads.spawn(async move {
+            let options = DownloadOptions {
+                concurrency: STREAMS_PER_CLIENT,
+                ..DownloadOptions::default()
+            };
+            client.download(url, files, options).await.unwrap()
+        });
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let sampler = {
+        let done = Arc::clone(&done);
+        let url = url.clone();
+        tokio::spawn(async move {
+            let mut latencies = vec![];
+            while !done.load(Ordering::SeqCst) {
+                latencies.append(&mut list_latencies(&probe, &url, 1).await);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            latencies
+        })
+    };
+
+    let mut bytes = 0;
+    while let Some(report) = downloads.join_next().await {
+        let report = report.unwrap();
+        assert!(report.failed.is_empty(), "{:?}", report.failed);
+        bytes += report.bytes;
+    }
+    let elapsed = start.elapsed();
+    done.store(true, Ordering::SeqCst);
+    let loaded = sampler.await.unwrap();
+
+    println!(
+        "{} streams, {} MiB in {:?}: {:.1} MiB/s",
+        CLIENTS * STREAMS_PER_CLIENT,
+        bytes / (1024 * 1024),
+        elapsed,
+        bytes as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0)
+    );
+    print_latencies("ListDirectories idle", idle);
+    print_latencies("ListDirectories under load", loaded);
+
+    let _ = fs::remove_dir_all(&base);
+}
+
+async fn list_latencies(client: &Client, url: &str, samples: usize) -> Vec<Duration> {
+    let mut latencies = vec![];
+    for _ in 0..samples {
+        let start = Instant::now();
+        client.list_directories(url.to_string()).await.unwrap();
+        latencies.push(start.elapsed());
+    }
+    latencies
+}
+
+fn print_latencies(label: &str, mut latencies: Vec<Duration>) {
+    if latencies.is_empty() {
+        println!("{}: no samples", label);
+        return;
+    }
+    latencies.sort();
+    let at = |q: f64| latencies[((latencies.len() - 1) as f64 * q) as usize];
+    println!(
+        "{}: {} samples, p50 {:?}, p99 {:?}, max {:?}",
+        label,
+        latencies.len(),
+        at(0.5),
+        at(0.99),
+        latencies[latencies.len() - 1]
+    );
+}
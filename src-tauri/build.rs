@@ -10,6 +10,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             "Directory",
             "#[derive(serde::Serialize, serde::Deserialize)]",
         )
+        .bytes(["."])
         .file_descriptor_set_path(out_dir.join("pb_descriptor.bin"))
         .out_dir("./src")
         .compile(&[proto_file], &["proto"])?;
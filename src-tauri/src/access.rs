@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use data_encoding::BASE64;
+use ring::{
+    digest::{digest, SHA256},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::error::LandohError;
+use crate::tls::KnownPeers;
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+/// Passwords remembered by a `PasswordCache` before it starts over.
+const MAX_VERIFIED: usize = 1024;
+
+/// Who may see and download a shared directory.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum Access {
+    /// Everyone on the network.
+    Public,
+    /// Peers that send the password. Only a salted hash of it is stored.
+    Password { salt: String, hash: String },
+    /// Peers with one of the listed ids or an address in one of the listed
+    /// networks, given as `192.168.1.0/24`, `fd00::/8` or single addresses.
+    /// Other peers do not see the share at all.
+    Restricted {
+        #[serde(default)]
+        peers: Vec<String>,
+        #[serde(default)]
+        networks: Vec<String>,
+    },
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Access::Public
+    }
+}
+
+/// What a server knows about the peer sending a request.
+#[derive(Debug, Default)]
+pub(crate) struct Requester {
+    pub(crate) ip: Option<IpAddr>,
+    /// Fingerprint of the certificate the peer presented, if any.
+    pub(crate) fingerprint: Option<String>,
+    pub(crate) password: Option<String>,
+}
+
+impl Access {
+    pub fn password(password: &str) -> Result<Self, LandohError> {
+        let mut salt = [0u8; SALT_LEN];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| LandohError::Internal("failed to generate salt".to_string()))?;
+
+        let mut hash = [0u8; HASH_LEN];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations(),
+            &salt,
+            password.as_bytes(),
+            &mut hash,
+        );
+        Ok(Access::Password {
+            salt: BASE64.encode(&salt),
+            hash: BASE64.encode(&hash),
+        })
+    }
+
+    /// Rejects allow-lists with networks that cannot be parsed, as they would
+    /// never match anyone.
+    pub fn validate(&self) -> Result<(), LandohError> {
+        if let Access::Restricted { networks, .. } = self {
+            for n in networks {
+                if parse_network(n).is_none() {
+                    return Err(LandohError::InvalidRequest(format!(
+                        "invalid network: {:?}",
+                        n
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the share shows up when `requester` lists directories.
+    /// Password protected shares are listed so their password can be asked
+    /// for.
+    pub(crate) fn is_visible(&self, requester: &Requester, known_peers: &KnownPeers) -> bool {
+        match self {
+            Access::Restricted { .. } => self.allows(requester, known_peers),
+            _ => true,
+        }
+    }
+
+    pub(crate) fn allows(&self, requester: &Requester, known_peers: &KnownPeers) -> bool {
+        match self {
+            Access::Public => true,
+            Access::Password { salt, hash } => {
+                let (salt, hash, password) = match (
+                    BASE64.decode(salt.as_bytes()),
+                    BASE64.decode(hash.as_bytes()),
+                    &requester.password,
+                ) {
+                    (Ok(s), Ok(h), Some(p)) => (s, h, p),
+                    _ => return false,
+                };
+                pbkdf2::verify(
+                    pbkdf2::PBKDF2_HMAC_SHA256,
+                    iterations(),
+                    &salt,
+                    password.as_bytes(),
+                    &hash,
+                )
+                .is_ok()
+            }
+            Access::Restricted { peers, networks } => {
+                let by_id = match &requester.fingerprint {
+                    Some(fp) => peers.iter().any(|id| known_peers.has_fingerprint(id, fp)),
+                    None => false,
+                };
+                let by_address = match requester.ip {
+                    Some(ip) => networks.iter().any(|n| in_network(ip, n)),
+                    None => false,
+                };
+                by_id || by_address
+            }
+        }
+    }
+}
+
+/// Passwords that were checked successfully, so peers do not pay for PBKDF2
+/// on every request. Entries are kept per certificate fingerprint and share,
+/// and only hold a SHA-256 of the password along with the stored hash it was
+/// checked against, so a changed password is checked again.
+#[derive(Debug, Default)]
+pub(crate) struct PasswordCache {
+    verified: Mutex<HashMap<(String, String), Verified>>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Verified {
+    /// The stored hash of the password.
+    stored: String,
+    /// SHA-256 of the password the peer sent.
+    sent: Vec<u8>,
+}
+
+impl PasswordCache {
+    /// Whether `requester` already sent the password `access` needs for `share`.
+    pub(crate) fn contains(&self, share: &str, access: &Access, requester: &Requester) -> bool {
+        let (key, entry) = match verified(share, access, requester) {
+            Some(v) => v,
+            None => return false,
+        };
+        self.lock().get(&key) == Some(&entry)
+    }
+
+    /// Remembers that `requester` sent the right password for `share`.
+    pub(crate) fn insert(&self, share: &str, access: &Access, requester: &Requester) {
+        if let Some((key, entry)) = verified(share, access, requester) {
+            let mut v = self.lock();
+            if v.len() >= MAX_VERIFIED {
+                v.clear();
+            }
+            v.insert(key, entry);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<(String, String), Verified>> {
+        self.verified.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The key and entry of a password check. Only peers with a certificate can
+/// be told apart, so there is none for the others.
+fn verified(
+    share: &str,
+    access: &Access,
+    requester: &Requester,
+) -> Option<((String, String), Verified)> {
+    match (access, &requester.fingerprint, &requester.password) {
+        (Access::Password { hash, .. }, Some(fp), Some(p)) => Some((
+            (fp.clone(), share.to_string()),
+            Verified {
+                stored: hash.clone(),
+                sent: digest(&SHA256, p.as_bytes()).as_ref().to_vec(),
+            },
+        )),
+        _ => None,
+    }
+}
+
+fn iterations() -> NonZeroU32 {
+    NonZeroU32::new(PBKDF2_ITERATIONS).unwrap()
+}
+
+/// Splits `addr/len` into the address and prefix length. A single address
+/// is a network of just that address.
+fn parse_network(network: &str) -> Option<(IpAddr, u32)> {
+    let (addr, len) = match network.trim().split_once('/') {
+        Some((a, l)) => (a.parse::<IpAddr>().ok()?, Some(l.parse::<u32>().ok()?)),
+        None => (network.trim().parse::<IpAddr>().ok()?, None),
+    };
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    match len {
+        Some(l) if l > max => None,
+        Some(l) => Some((addr, l)),
+        None => Some((addr, max)),
+    }
+}
+
+/// Dual-stack sockets report IPv4 peers as mapped IPv6 addresses, this turns
+/// them back into IPv4 ones.
+pub(crate) fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+fn in_network(ip: IpAddr, network: &str) -> bool {
+    let (net, len) = match parse_network(network) {
+        Some(n) => n,
+        None => return false,
+    };
+    let ip = unmapped(ip);
+
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+#[test]
+fn test_access_rules() {
+    let known_peers = KnownPeers::default();
+    known_peers.remember_fingerprint("friend", "F");
+    let anyone = Requester {
+        ip: Some("192.168.1.20".parse().unwrap()),
+        ..Requester::default()
+    };
+
+    assert!(Access::Public.allows(&anyone, &known_peers));
+
+    let protected = Access::password("secret").unwrap();
+    assert!(!protected.allows(&anyone, &known_peers));
+    assert!(protected.is_visible(&anyone, &known_peers));
+    let wrong = Requester {
+        password: Some("guess".to_string()),
+        ..Requester::default()
+    };
+    assert!(!protected.allows(&wrong, &known_peers));
+    let right = Requester {
+        password: Some("secret".to_string()),
+        ..Requester::default()
+    };
+    assert!(protected.allows(&right, &known_peers));
+
+    let cache = PasswordCache::default();
+    let peer = |password: &str| Requester {
+        fingerprint: Some("P".to_string()),
+        password: Some(password.to_string()),
+        ..Requester::default()
+    };
+    cache.insert("share", &protected, &peer("secret"));
+    assert!(cache.contains("share", &protected, &peer("secret")));
+    assert!(!cache.contains("share", &protected, &peer("guess")));
+    assert!(!cache.contains("other", &protected, &peer("secret")));
+    // A new password has a new salt and hash.
+    let changed = Access::password("secret").unwrap();
+    assert!(!cache.contains("share", &changed, &peer("secret")));
+    cache.insert("share", &protected, &right);
+    assert!(!cache.contains("share", &protected, &right));
+
+    let restricted = Access::Restricted {
+        peers: vec!["friend".to_string()],
+        networks: vec!["10.0.0.0/8".to_string(), "fd00::1".to_string()],
+    };
+    restricted.validate().unwrap();
+    assert!(!restricted.is_visible(&anyone, &known_peers));
+    for (ip, allowed) in [
+        ("10.1.2.3", true),
+        ("::ffff:10.1.2.3", true),
+        ("11.0.0.1", false),
+        ("fd00::1", true),
+        ("fd00::2", false),
+    ] {
+        let r = Requester {
+            ip: Some(ip.parse().unwrap()),
+            ..Requester::default()
+        };
+        assert_eq!(restricted.allows(&r, &known_peers), allowed, "{}", ip);
+    }
+
+    // Peers are recognized by the certificate they announced, not their id.
+    let friend = Requester {
+        fingerprint: Some("F".to_string()),
+        ..Requester::default()
+    };
+    assert!(restricted.allows(&friend, &known_peers));
+    let impostor = Requester {
+        fingerprint: Some("X".to_string()),
+        ..Requester::default()
+    };
+    assert!(!restricted.allows(&impostor, &known_peers));
+
+    assert!(Access::Restricted {
+        peers: vec![],
+        networks: vec!["10.0.0.0/33".to_string()],
+    }
+    .validate()
+    .is_err());
+}
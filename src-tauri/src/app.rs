@@ -1,6 +1,6 @@
 use std::{
+    collections::HashMap,
     env,
-    error::Error,
     fmt,
     fs::{self, File, OpenOptions},
     io::{Read, Write},
@@ -10,7 +10,6 @@ use std::{
         mpsc::{self, Receiver},
         Arc,
     },
-    thread,
     time::Duration,
 };
 
@@ -23,59 +22,149 @@ use uuid::Uuid;
 
 use tokio::task::JoinSet;
 
+use crate::client::{ChunkSize, Compression, DownloadOptions};
+use crate::error::LandohError;
+use crate::model::CHUNK_SIZE;
 use crate::multicast::{
     receiver::{self, Source},
     Sender,
 };
-
+use crate::source::{ANNOUNCE_INTERVAL, PEER_TTL};
+
+pub use crate::access::Access;
+pub use crate::consent::{ApprovalRequest, Approvals, FileOffer, IncomingFiles};
+pub use crate::group::GroupKey;
+pub use crate::interface::InterfaceFilter;
+pub use crate::limit::{Limiter, RateLimits};
+pub use crate::queue::TransferLimits;
 pub use crate::server::{Directory, Server};
-
-#[derive(serde::Serialize, serde::Deserialize)]
-pub struct Payload<T> {
-    id: String,
-    data: PayloadEnum<T>,
-}
-
-#[derive(serde::Serialize, serde::Deserialize)]
-pub enum PayloadEnum<T> {
-    One(T),
-    Many(T),
-}
+pub use crate::signing::SigningKey;
+pub use crate::tls::{Identity, KnownPeers};
 
 pub type Sources = Arc<Mutex<Vec<Source>>>;
 
-fn save_config(config: &Config) -> Result<(), Box<dyn Error>> {
-    let path = config_path();
+fn save_config(config: &Config) -> Result<(), LandohError> {
+    let path = config_path()?;
 
     let mut f = OpenOptions::new();
 
-    fs::create_dir_all(path.parent().unwrap())?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
 
     fs::remove_file(&path).unwrap_or_default();
 
     let mut file = f.write(true).create(true).open(&path)?;
 
-    let payload = serde_json::to_string_pretty(&config)?;
+    let payload = serde_json::to_string_pretty(&config)
+        .map_err(|err| LandohError::Config(err.to_string()))?;
     file.write_all(payload.as_bytes())?;
 
     Ok(())
 }
 
+fn env_path(var: &str) -> Result<String, LandohError> {
+    env::var(var).map_err(|err| LandohError::Config(format!("{}: {}", var, err)))
+}
+
 #[cfg(windows)]
-fn config_path() -> PathBuf {
-    let mut appdata = env::var("APPDATA").unwrap();
+fn config_path() -> Result<PathBuf, LandohError> {
+    let mut appdata = env_path("APPDATA")?;
 
     appdata.extend(["/", "LANdoh", "/", "config.ini"]);
-    PathBuf::from(appdata)
+    Ok(PathBuf::from(appdata))
 }
 
 #[cfg(unix)]
-fn config_path() -> PathBuf {
-    let mut appdata = env::var("HOME").unwrap();
+fn config_path() -> Result<PathBuf, LandohError> {
+    let mut appdata = env_path("HOME")?;
 
     appdata.extend(["/", ".landoh_config"]);
 
-    PathBuf::from(appdata)
+    Ok(PathBuf::from(appdata))
+}
+
+#[cfg(windows)]
+pub(crate) fn hash_cache_path() -> Result<PathBuf, LandohError> {
+    let mut appdata = env_path("APPDATA")?;
+
+    appdata.extend(["/", "LANdoh", "/", "hashes.json"]);
+    Ok(PathBuf::from(appdata))
+}
+
+#[cfg(unix)]
+pub(crate) fn hash_cache_path() -> Result<PathBuf, LandohError> {
+    let mut appdata = env_path("HOME")?;
+
+    appdata.extend(["/", ".landoh_hashes"]);
+
+    Ok(PathBuf::from(appdata))
+}
+
+#[cfg(windows)]
+fn identity_path() -> Result<PathBuf, LandohError> {
+    let mut appdata = env_path("APPDATA")?;
+
+    appdata.extend(["/", "LANdoh", "/", "identity.pem"]);
+    Ok(PathBuf::from(appdata))
+}
+
+#[cfg(unix)]
+fn identity_path() -> Result<PathBuf, LandohError> {
+    let mut appdata = env_path("HOME")?;
+
+    appdata.extend(["/", ".landoh_identity"]);
+
+    Ok(PathBuf::from(appdata))
+}
+
+#[cfg(windows)]
+fn known_peers_path() -> Result<PathBuf, LandohError> {
+    let mut appdata = env_path("APPDATA")?;
+
+    appdata.extend(["/", "LANdoh", "/", "peers.json"]);
+    Ok(PathBuf::from(appdata))
+}
+
+#[cfg(unix)]
+fn known_peers_path() -> Result<PathBuf, LandohError> {
+    let mut appdata = env_path("HOME")?;
+
+    appdata.extend(["/", ".landoh_peers"]);
+
+    Ok(PathBuf::from(appdata))
+}
+
+#[cfg(windows)]
+fn signing_key_path() -> Result<PathBuf, LandohError> {
+    let mut appdata = env_path("APPDATA")?;
+
+    appdata.extend(["/", "LANdoh", "/", "signing.key"]);
+    Ok(PathBuf::from(appdata))
+}
+
+#[cfg(unix)]
+fn signing_key_path() -> Result<PathBuf, LandohError> {
+    let mut appdata = env_path("HOME")?;
+
+    appdata.extend(["/", ".landoh_signing_key"]);
+
+    Ok(PathBuf::from(appdata))
+}
+
+/// The peers this node pinned keys for, as stored next to the config.
+pub fn known_peers() -> Result<KnownPeers, LandohError> {
+    Ok(KnownPeers::load(known_peers_path()?))
+}
+
+/// The certificate this node serves and connects to peers with.
+pub fn identity() -> Result<Identity, LandohError> {
+    Identity::load_or_generate(identity_path()?)
+}
+
+/// The key this node signs its announcements with.
+pub fn signing_key() -> Result<SigningKey, LandohError> {
+    SigningKey::load_or_generate(signing_key_path()?)
 }
 
 #[allow(dead_code)]
@@ -103,58 +192,87 @@ pub struct App {
     pub handles: JoinSet<()>,
     sender: Arc<Mutex<Sender>>,
     pub sources: Sources,
+    /// Enforces `Config::limits` for the server and downloads.
+    pub limiter: Arc<Limiter>,
+    /// The key this node serves with, generated on first start.
+    pub identity: Identity,
+    /// The key this node signs its announcements with.
+    pub signing_key: Arc<SigningKey>,
+    pub known_peers: Arc<KnownPeers>,
+    /// Derived from `Config::group`.
+    pub group: Option<Arc<GroupKey>>,
+    /// Requests of peers waiting for the owner's approval.
+    pub approvals: Arc<Approvals>,
+    /// Files peers offer to send, waiting for the owner to accept them.
+    pub incoming: Arc<IncomingFiles>,
 }
 
 impl App {
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Config) -> Result<Self, LandohError> {
         set_loglevel(LogLevel::INFO);
         let _ = env_logger::try_init();
 
-        save_config(&config).unwrap();
+        save_config(&config)?;
+        let group = config.group.as_deref().map(|g| Arc::new(GroupKey::new(g)));
+        let sender = Sender::new(group.clone(), &config.interfaces)?;
 
-        App {
+        Ok(App {
+            limiter: Arc::new(Limiter::new(&config.limits)),
+            identity: identity()?,
+            signing_key: Arc::new(signing_key()?),
+            known_peers: Arc::new(known_peers()?),
             config: Arc::new(Mutex::new(config)),
             handles: JoinSet::new(),
-            sender: Arc::new(Mutex::new(Sender::new().unwrap())),
+            sender: Arc::new(Mutex::new(sender)),
             sources: Arc::new(Mutex::new(vec![])),
-        }
+            group,
+            approvals: Arc::new(Approvals::default()),
+            incoming: Arc::new(IncomingFiles::default()),
+        })
     }
 
-    pub fn new_from_config() -> Result<Self, Box<dyn Error>> {
+    pub fn new_from_config() -> Result<Self, LandohError> {
         set_loglevel(LogLevel::INFO);
 
         let _ = env_logger::try_init();
 
-        let path = config_path();
+        let path = config_path()?;
         match path.exists() {
             true => match path.is_file() {
                 true => {
                     let mut f = File::open(path)?;
                     let mut s: String = "".to_string();
                     let _ = f.read_to_string(&mut s)?;
-                    let c: Config = serde_json::from_str::<Config>(&s)?;
-                    return Ok(Self::new(c));
+                    let c: Config = serde_json::from_str::<Config>(&s)
+                        .map_err(|err| LandohError::Config(err.to_string()))?;
+                    return Self::new(c);
                 }
                 false => {
-                    return Err("Config not found".into());
+                    return Err(LandohError::Config("Config not found".to_string()));
                 }
             },
             false => {
-                return Err("Config file not found".into());
+                return Err(LandohError::Config("Config file not found".to_string()));
             }
         };
     }
 
-    pub async fn save_config(&self) {
-        save_config(&self.config.lock().await.clone()).unwrap();
+    pub async fn save_config(&self) -> Result<(), LandohError> {
+        save_config(&self.config.lock().await.clone())
     }
 
     pub async fn listen(&mut self) -> Receiver<Vec<Source>> {
         let s = Arc::clone(&self.sources);
         let id = self.config.lock().await.id.to_string();
+        let known_peers = Arc::clone(&self.known_peers);
+        let group = self.group.clone();
+        let (interfaces, ttl) = {
+            let c = self.config.lock().await;
+            (c.interfaces.clone(), c.peer_ttl())
+        };
         let (tx, rx) = mpsc::channel::<Vec<Source>>();
         self.handles.spawn(async move {
-            let _ = receiver::listen(id, s, Some(tx)).await;
+            let _ = receiver::listen(id, s, Some(tx), known_peers, group, interfaces, ttl).await;
         });
         rx
     }
@@ -162,35 +280,70 @@ impl App {
     pub async fn broadcast(&mut self) {
         let s = Arc::clone(&self.sender);
         let dirs = Arc::clone(&self.config);
+        let fingerprint = self.identity.fingerprint().to_string();
+        let key = Arc::clone(&self.signing_key);
         tokio::spawn(async move {
+            // Nothing is announced while nothing is shared, peers are told
+            // once when sharing stops.
+            let mut sharing = false;
             loop {
-                {
+                let interval = {
                     let c = dirs.lock().await;
-                    let d = Source::new(
-                        c.id.to_string().clone(),
-                        c.nickname.to_string().clone(),
-                        None,
-                        c.shared_directories
-                            .clone()
-                            .iter()
-                            .map(|d| d.name.clone())
-                            .collect(),
-                    );
-                    let _ = s.lock().await.send(d).await;
-                }
-                thread::sleep(Duration::from_secs(5));
+                    let d = announcement(&c, &fingerprint);
+                    let was_sharing = sharing;
+                    sharing = !d.shared_directories.is_empty();
+                    let d = match (sharing, was_sharing) {
+                        (true, _) => Some(d),
+                        (false, true) => Some(d.leaving()),
+                        (false, false) => None,
+                    };
+                    if let Some(a) = d.and_then(|d| key.sign(&d).ok()) {
+                        let _ = s.lock().await.send(a).await;
+                    }
+                    c.announce_interval()
+                };
+                tokio::time::sleep(interval).await;
             }
         });
     }
 
-    pub async fn publish(&self, payload: Source) -> Result<(), Box<dyn Error>> {
-        self.sender.lock().await.send(payload).await
+    pub async fn publish(&self, payload: Source) -> Result<(), LandohError> {
+        let announcement = self.signing_key.sign(&payload)?;
+        self.sender.lock().await.send(announcement).await
+    }
+
+    /// Tells peers this node is gone, so they drop it before it expires.
+    pub async fn goodbye(&self) -> Result<(), LandohError> {
+        let c = self.config.lock().await.clone();
+        self.publish(announcement(&c, self.identity.fingerprint()).leaving())
+            .await
+    }
+
+    /// Drops the peers that were not heard of for the configured TTL and
+    /// tells whether there were any.
+    pub async fn remove_outdated(&self) -> bool {
+        let ttl = self.config.lock().await.peer_ttl();
+        let mut sources = self.sources.lock().await;
+        let len = sources.len();
+        sources.retain(|s| !s.is_outdated(ttl));
+        len != sources.len()
     }
 
     pub async fn serve(&mut self) {
         let s = self.config.lock().await;
         let c = Arc::clone(&self.config);
-        let server = Server::new(c);
+        let mut server = Server::new(
+            c,
+            Arc::clone(&self.limiter),
+            self.identity.clone(),
+            Arc::clone(&self.known_peers),
+        );
+        if let Some(g) = &self.group {
+            server = server.with_group(Arc::clone(g));
+        }
+        let server = server
+            .with_approvals(Arc::clone(&self.approvals))
+            .with_incoming(Arc::clone(&self.incoming));
         let addr = s.address;
         let _ = env_logger::try_init();
 
@@ -205,29 +358,25 @@ impl App {
         });
     }
 
+    /// Applies new bandwidth limits, including to running transfers.
+    pub async fn set_limits(&self, limits: RateLimits) {
+        self.config.lock().await.limits = limits;
+        self.limiter.set(&limits);
+    }
+
     pub async fn join_all(&mut self) {
         while let Some(_) = self.handles.join_next().await {}
     }
 
+    /// Shares `paths` as `name`. If a share with that name exists the paths are
+    /// added to it, since a share is the union of all of its paths.
     pub async fn add_shared_dir(
         &mut self,
         name: String,
         paths: Vec<String>,
-    ) -> Result<(), Box<dyn Error>> {
-        let mut na = name;
+    ) -> Result<(), LandohError> {
         let mut existing_paths = vec![];
 
-        if self
-            .config
-            .lock()
-            .await
-            .shared_directories
-            .iter()
-            .any(|d| d.name == na)
-        {
-            return Ok(());
-        }
-
         for pa in paths {
             let p = PathBuf::from(&pa);
             if p.exists() && p.metadata()?.is_dir() {
@@ -235,44 +384,36 @@ impl App {
             }
         }
 
-        let p = PathBuf::from(&na);
-        if p.exists() && p.is_dir() {
-            na = match p.file_name() {
-                Some(n) => n.to_str().unwrap().to_string(),
-                None => na,
-            }
+        if existing_paths.is_empty() {
+            return Err(LandohError::InvalidRequest(format!(
+                "no existing directory to share as '{}'",
+                name
+            )));
         }
 
-        let dir = Directory {
-            name: na,
-            paths: existing_paths,
-        };
+        let na = share_name(&name);
 
         {
-            let _ = &self
-                .config
-                .lock()
-                .await
-                .shared_directories
-                // .lock()
-                // .await
-                .push(dir.clone());
+            let mut c = self.config.lock().await;
+            match c.shared_directories.iter_mut().find(|d| d.name == na) {
+                Some(d) => {
+                    for p in existing_paths {
+                        if !d.paths.contains(&p) {
+                            d.paths.push(p);
+                        }
+                    }
+                }
+                None => c.shared_directories.push(Directory {
+                    name: na,
+                    paths: existing_paths,
+                }),
+            }
         }
         {
             let c = self.config.lock().await;
 
-            self.publish(Source::new(
-                c.id.to_string(),
-                c.nickname.clone(),
-                None,
-                c.shared_directories
-                    // .lock()
-                    // .await
-                    .iter()
-                    .map(|i| i.name.clone())
-                    .collect(),
-            ))
-            .await?;
+            self.publish(announcement(&c, self.identity.fingerprint()))
+                .await?;
         }
         Ok(())
     }
@@ -285,7 +426,74 @@ impl App {
             // .lock()
             // .await
             .retain(|d| d.name != name);
+        self.config.lock().await.access.remove(&name);
+    }
+
+    /// Removes one path from a share and drops the share once it has none left.
+    pub async fn remove_shared_path(&mut self, name: String, path: String) {
+        let mut c = self.config.lock().await;
+        if let Some(d) = c.shared_directories.iter_mut().find(|d| d.name == name) {
+            d.paths.retain(|p| p != &path);
+        }
+        c.shared_directories.retain(|d| !d.paths.is_empty());
+        if !c.shared_directories.iter().any(|d| d.name == name) {
+            c.access.remove(&name);
+        }
+    }
+
+    /// Answers the requests of `peer` waiting for approval. Remembered answers
+    /// also apply after a restart.
+    pub async fn answer_approval(&self, peer: String, approve: bool, remember: bool) {
+        self.approvals.answer(&peer, approve);
+        if remember {
+            self.config
+                .lock()
+                .await
+                .remembered_peers
+                .insert(peer, approve);
+        }
+    }
+
+    /// Sets who may see and download the share `name`.
+    pub async fn set_access(&self, name: String, access: Access) -> Result<(), LandohError> {
+        access.validate()?;
+        let mut c = self.config.lock().await;
+        if !c.shared_directories.iter().any(|d| d.name == name) {
+            return Err(LandohError::InvalidRequest(format!(
+                "no share named '{}'",
+                name
+            )));
+        }
+        match access {
+            Access::Public => c.access.remove(&name),
+            a => c.access.insert(name, a),
+        };
+        Ok(())
+    }
+}
+
+/// What this node announces about itself.
+fn announcement(c: &Config, fingerprint: &str) -> Source {
+    Source::new(
+        c.id.to_string(),
+        c.nickname.clone(),
+        None,
+        c.shared_directories.iter().map(|d| d.name.clone()).collect(),
+    )
+    .with_fingerprint(fingerprint.to_string())
+    .with_port(c.address.port())
+}
+
+/// The name a directory is shared as: its last component if `name` is an
+/// existing directory, `name` itself otherwise.
+fn share_name(name: &str) -> String {
+    let p = PathBuf::from(name);
+    if p.is_dir() {
+        if let Some(n) = p.file_name() {
+            return n.to_string_lossy().to_string();
+        }
     }
+    name.to_string()
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -295,6 +503,56 @@ pub struct Config {
     pub shared_directories: Vec<Directory>,
     pub destination: PathBuf,
     pub address: SocketAddr,
+    /// Chunk size used when serving files to peers that do not ask for one.
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: usize,
+    /// Tune the chunk size of downloads from measured throughput.
+    #[serde(default)]
+    pub adaptive_chunk_size: bool,
+    /// Codec peers are asked to compress downloads with.
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub limits: RateLimits,
+    #[serde(default)]
+    pub transfers: TransferLimits,
+    /// Who may see and download each share, by name. Shares not listed are
+    /// public.
+    #[serde(default)]
+    pub access: HashMap<String, Access>,
+    /// Passphrase of the private group this node is in. Members only
+    /// discover and talk to each other. Changes apply after a restart.
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Ask the owner before serving peers that were not approved yet.
+    #[serde(default)]
+    pub ask_before_serving: bool,
+    /// Answers to approval requests the owner asked to remember, by peer.
+    #[serde(default)]
+    pub remembered_peers: HashMap<String, bool>,
+    /// Network interfaces peers are discovered on. Changes apply after a
+    /// restart.
+    #[serde(default)]
+    pub interfaces: InterfaceFilter,
+    /// Seconds between the announcements of this node.
+    #[serde(default = "default_announce_interval")]
+    pub announce_interval: u64,
+    /// Seconds peers are kept after their last announcement, should span a
+    /// few of their announce intervals.
+    #[serde(default = "default_peer_ttl")]
+    pub peer_ttl: u64,
+}
+
+fn default_chunk_size() -> usize {
+    CHUNK_SIZE
+}
+
+fn default_announce_interval() -> u64 {
+    ANNOUNCE_INTERVAL
+}
+
+fn default_peer_ttl() -> u64 {
+    PEER_TTL
 }
 
 impl Config {
@@ -303,12 +561,12 @@ impl Config {
         destination: String,
         address: SocketAddr,
         nickname: Option<String>,
-    ) -> Result<Self, Box<dyn Error>> {
+    ) -> Result<Self, LandohError> {
         let id = Uuid::new_v4().to_string();
         let dirs = shared_directories
             .iter()
             .map(|d| Directory {
-                name: d.to_string(),
+                name: share_name(d),
                 paths: vec![d.to_string()],
             })
             .collect();
@@ -326,6 +584,44 @@ impl Config {
             nickname: nick,
             destination: dest,
             address: address,
+            chunk_size: CHUNK_SIZE,
+            adaptive_chunk_size: false,
+            compression: Compression::default(),
+            limits: RateLimits::default(),
+            transfers: TransferLimits::default(),
+            access: HashMap::new(),
+            group: None,
+            ask_before_serving: false,
+            remembered_peers: HashMap::new(),
+            interfaces: InterfaceFilter::default(),
+            announce_interval: ANNOUNCE_INTERVAL,
+            peer_ttl: PEER_TTL,
         })
     }
+
+    pub fn announce_interval(&self) -> Duration {
+        Duration::from_secs(self.announce_interval.max(1))
+    }
+
+    /// Capped at a year, longer ones are as good as never expiring.
+    pub fn peer_ttl(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.peer_ttl.min(365 * 24 * 60 * 60) as i64)
+    }
+
+    pub fn access_for(&self, share: &str) -> Access {
+        self.access.get(share).cloned().unwrap_or_default()
+    }
+
+    /// Options for downloads started from the UI.
+    pub fn download_options(&self) -> DownloadOptions {
+        DownloadOptions {
+            chunk_size: if self.adaptive_chunk_size {
+                ChunkSize::Adaptive
+            } else {
+                ChunkSize::Peer
+            },
+            compression: self.compression,
+            ..DownloadOptions::default()
+        }
+    }
 }
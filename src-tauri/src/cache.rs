@@ -0,0 +1,167 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, Metadata, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::SystemTime,
+};
+
+use data_encoding::HEXUPPER;
+use log::warn;
+use ring::digest::{Context, SHA256};
+use serde::{Deserialize, Serialize};
+
+use crate::error::LandohError;
+use crate::model::CHUNK_SIZE;
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+struct Entry {
+    size: u64,
+    modified: SystemTime,
+    hash: String,
+}
+
+/// SHA-256 hashes of shared files, keyed by canonical path and only trusted
+/// as long as size and modification time still match what was recorded.
+#[derive(Deserialize, Serialize, Default, Debug)]
+pub struct HashCache {
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    #[serde(skip)]
+    dirty: bool,
+    entries: HashMap<String, Entry>,
+}
+
+impl HashCache {
+    pub fn load(path: PathBuf) -> Self {
+        let mut cache = match fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str::<HashCache>(&s).unwrap_or_else(|err| {
+                warn!("discarding unreadable hash cache {:?}: {}", &path, err);
+                HashCache::default()
+            }),
+            Err(_) => HashCache::default(),
+        };
+        cache.path = Some(path);
+        cache
+    }
+
+    pub fn get(&self, path: &Path, meta: &Metadata) -> Option<String> {
+        let modified = meta.modified().ok()?;
+        match self.entries.get(path.to_str()?) {
+            Some(e) if e.size == meta.len() && e.modified == modified => Some(e.hash.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, path: &Path, meta: &Metadata, hash: String) {
+        let (key, modified) = match (path.to_str(), meta.modified()) {
+            (Some(k), Ok(m)) => (k, m),
+            _ => return,
+        };
+        self.entries.insert(
+            key.to_string(),
+            Entry {
+                size: meta.len(),
+                modified,
+                hash,
+            },
+        );
+        self.dirty = true;
+    }
+
+    /// Drops entries whose files are gone and writes the cache to disk if it changed.
+    pub fn save(&mut self) -> Result<(), LandohError> {
+        let len = self.entries.len();
+        self.entries.retain(|p, _| Path::new(p).is_file());
+        if !self.dirty && len == self.entries.len() {
+            return Ok(());
+        }
+
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let payload = serde_json::to_string(&self).map_err(io::Error::from)?;
+        file.write_all(payload.as_bytes())?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+fn lock(cache: &Mutex<HashCache>) -> MutexGuard<'_, HashCache> {
+    cache.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Returns the recorded hash of `path` if it is still current. Blocking.
+pub fn cached_hash(cache: &Mutex<HashCache>, path: &Path) -> Option<String> {
+    let path = path.canonicalize().ok()?;
+    let meta = path.metadata().ok()?;
+    lock(cache).get(&path, &meta)
+}
+
+/// Returns the cached hash for `path` or hashes the file and records it. The
+/// lock is only held to look up and record the hash, never while the file is
+/// read. Blocking.
+pub fn hash_shared(cache: &Mutex<HashCache>, path: &Path, meta: &Metadata) -> io::Result<String> {
+    let path = path.canonicalize()?;
+    if let Some(h) = lock(cache).get(&path, meta) {
+        return Ok(h);
+    }
+    let hash = hash_file(&path)?;
+    lock(cache).insert(&path, meta, hash.clone());
+    Ok(hash)
+}
+
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut reader = File::open(path)?;
+    let mut context = Context::new(&SHA256);
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let count = reader.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        context.update(&buffer[..count]);
+    }
+    Ok(HEXUPPER.encode(context.finish().as_ref()))
+}
+
+#[test]
+fn test_hash_cache_invalidation() {
+    let dir = std::env::temp_dir().join(format!("landoh-cache-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("data");
+    let index = dir.join("hashes.json");
+
+    fs::write(&file, b"first").unwrap();
+    let cache = Mutex::new(HashCache::load(index.clone()));
+    let first = hash_shared(&cache, &file, &file.metadata().unwrap()).unwrap();
+    assert_eq!(first, hash_file(&file).unwrap());
+    lock(&cache).save().unwrap();
+
+    let cache = HashCache::load(index.clone());
+    assert_eq!(cache.get(&file, &file.metadata().unwrap()), Some(first.clone()));
+
+    fs::write(&file, b"second version").unwrap();
+    let cache = Mutex::new(HashCache::load(index));
+    assert_eq!(cached_hash(&cache, &file), None);
+    let second = hash_shared(&cache, &file, &file.metadata().unwrap()).unwrap();
+    assert_ne!(first, second);
+
+    // Shared paths are recorded canonically, however they were reached.
+    let indirect = dir.join(".").join("data");
+    assert_eq!(cached_hash(&cache, &indirect), Some(second));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use crate::model::{CHUNK_SIZE, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+
+/// Transfers shorter than this many chunks say more about latency than about
+/// the chunk size, so they are not used for tuning.
+const MIN_SAMPLE_CHUNKS: u64 = 16;
+
+/// Keeps `size` within the bounds a peer is allowed to ask for.
+pub fn clamp_chunk_size(size: usize) -> usize {
+    size.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE)
+}
+
+/// The chunk size a client asks for in its GetFile requests.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkSize {
+    /// Leave it to the serving peer's configuration.
+    Peer,
+    Fixed(usize),
+    /// Start at the default and tune the size from measured throughput.
+    Adaptive,
+}
+
+/// Picks the chunk size for the next request of a download. In adaptive mode
+/// it doubles or halves the size for as long as that improves throughput and
+/// turns around once it does not.
+#[derive(Debug)]
+pub struct ChunkTuner {
+    size: Option<usize>,
+    adaptive: bool,
+    growing: bool,
+    best: f64,
+}
+
+impl ChunkTuner {
+    pub fn new(mode: &ChunkSize) -> Self {
+        let (size, adaptive) = match mode {
+            ChunkSize::Peer => (None, false),
+            ChunkSize::Fixed(s) => (Some(clamp_chunk_size(*s)), false),
+            ChunkSize::Adaptive => (Some(CHUNK_SIZE), true),
+        };
+
+        ChunkTuner {
+            size,
+            adaptive,
+            growing: true,
+            best: 0.0,
+        }
+    }
+
+    pub fn size(&self) -> Option<usize> {
+        self.size
+    }
+
+    /// Records a transfer of `bytes` in `elapsed` that used chunks of `size`.
+    pub fn record(&mut self, size: usize, bytes: u64, elapsed: Duration) {
+        if !self.adaptive || self.size != Some(size) {
+            return;
+        }
+        let secs = elapsed.as_secs_f64();
+        if bytes < size as u64 * MIN_SAMPLE_CHUNKS || secs <= 0.0 {
+            return;
+        }
+
+        let throughput = bytes as f64 / secs;
+        if throughput < self.best * 0.95 {
+            self.growing = !self.growing;
+        }
+        self.best = self.best.max(throughput);
+
+        let next = if self.growing { size * 2 } else { size / 2 };
+        let next = clamp_chunk_size(next);
+        if next == size {
+            self.growing = !self.growing;
+        }
+        self.size = Some(next);
+    }
+}
+
+#[test]
+fn test_chunk_tuner_converges() {
+    // Loopback-like curve: throughput rises with the chunk size up to 256 KiB
+    // and drops again above it.
+    let throughput = |size: usize| {
+        let kib = (size / 1024) as f64;
+        1e9 - (kib.log2() - 8.0).abs() * 1e8
+    };
+
+    let mut tuner = ChunkTuner::new(&ChunkSize::Adaptive);
+    let mut seen = vec![];
+    for _ in 0..20 {
+        let size = tuner.size().unwrap();
+        seen.push(size);
+        let bytes = 256 * 1024 * 1024;
+        let secs = bytes as f64 / throughput(size);
+        tuner.record(size, bytes, Duration::from_secs_f64(secs));
+    }
+
+    for size in &seen[10..] {
+        assert!(
+            (128 * 1024..=512 * 1024).contains(size),
+            "did not settle: {:?}",
+            seen
+        );
+    }
+}
+
+#[test]
+fn test_chunk_tuner_fixed() {
+    let mut tuner = ChunkTuner::new(&ChunkSize::Fixed(1));
+    assert_eq!(tuner.size(), Some(MIN_CHUNK_SIZE));
+    tuner.record(MIN_CHUNK_SIZE, 1 << 30, Duration::from_secs(1));
+    assert_eq!(tuner.size(), Some(MIN_CHUNK_SIZE));
+
+    assert_eq!(ChunkTuner::new(&ChunkSize::Peer).size(), None);
+    assert_eq!(clamp_chunk_size(usize::MAX), MAX_CHUNK_SIZE);
+}
@@ -1,122 +1,785 @@
 use std::{
-    error::Error,
-    fs::{self, OpenOptions},
-    io::Write,
-    path::PathBuf,
-    sync::Arc,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, PoisonError,
+    },
+    time::{Duration, Instant},
 };
 
+use bytes::Bytes;
 use data_encoding::HEXUPPER;
 use ring::digest::{Context, SHA256};
-use tokio_stream::StreamExt;
+use tokio::{
+    sync::{mpsc, Mutex, Notify, Semaphore},
+    task::JoinSet,
+};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tonic::{codegen::InterceptedService, transport::Channel, Status};
 
 use super::pb::{
-    get_file_response::FileResponse, lan_doh_client, FileMetaData, GetDirectoryRequest,
-    GetFileRequest, ListDirectoriesRequest,
+    self, get_file_response::FileResponse, lan_doh_client, send_file_request::SendRequest,
+    CompressedChunk, FileMetaData, GetDirectoryRequest, GetFileRequest, ListDirectoriesRequest,
+    SendFileRequest,
 };
 
-use log::{error, info};
+use log::{error, info, warn};
+
+use walkdir::WalkDir;
+
+use crate::cache::hash_file;
+use crate::chunk::ChunkTuner;
+use crate::error::LandohError;
+use crate::group::{GroupKey, GroupToken};
+use crate::limit::Limiter;
+use crate::model::CHUNK_SIZE;
+use crate::server::received_path;
+use crate::tls::{self, Identity, KnownPeers};
+
+pub use crate::chunk::ChunkSize;
+pub use crate::compression::Compression;
+
+/// Files larger than this are split into ranges of this size when swarming,
+/// so several peers can serve the same file.
+const SWARM_RANGE_SIZE: u64 = 64 * 1024 * 1024;
+/// Number of chunks read ahead of a SendFile stream.
+const SEND_BUFFER: usize = 16;
+
+type PeerClient = lan_doh_client::LanDohClient<InterceptedService<Channel, GroupToken>>;
+
+/// A unit of work in a swarm download: a whole file or a byte range of one.
+#[derive(Debug, Clone)]
+struct Segment {
+    file: FileMetaData,
+    offset: u64,
+    length: Option<u64>,
+}
+
+/// What a sync of one shared directory is going to do to the local destination.
+#[derive(Debug, Default)]
+pub struct SyncPlan {
+    pub download: Vec<FileMetaData>,
+    pub replace: Vec<FileMetaData>,
+    pub unchanged: Vec<String>,
+    pub delete: Vec<PathBuf>,
+}
+
+impl SyncPlan {
+    pub fn is_empty(&self) -> bool {
+        self.download.is_empty() && self.replace.is_empty() && self.delete.is_empty()
+    }
+}
+
+impl fmt::Display for SyncPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "new: {}, changed: {}, unchanged: {}, delete: {}",
+            self.download.len(),
+            self.replace.len(),
+            self.unchanged.len(),
+            self.delete.len()
+        )?;
+        for d in &self.download {
+            writeln!(f, "  + {}", d.path)?;
+        }
+        for r in &self.replace {
+            writeln!(f, "  ~ {}", r.path)?;
+        }
+        for d in &self.delete {
+            writeln!(f, "  - {}", d.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// How often a failed file transfer is retried and what happens to a download
+/// that does not match its hash.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts per file, including the first one.
+    pub attempts: u32,
+    /// Delay before the next attempt, multiplied by the attempt number.
+    pub backoff: Duration,
+    /// Keep corrupt downloads as `<file>.corrupt` instead of deleting them.
+    pub quarantine: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_secs(1),
+            quarantine: false,
+        }
+    }
+}
+
+/// The order in which `Client::download` schedules files.
+#[derive(Debug, Clone)]
+pub enum DownloadOrder {
+    Listed,
+    SmallestFirst,
+    /// Files below any of these path prefixes come first, in the given order,
+    /// followed by everything else smallest first.
+    Priority(Vec<String>),
+}
+
+impl DownloadOrder {
+    fn sort(&self, files: &mut [FileMetaData]) {
+        match self {
+            DownloadOrder::Listed => {}
+            DownloadOrder::SmallestFirst => files.sort_by_key(|f| f.file_size),
+            DownloadOrder::Priority(prefixes) => files.sort_by_key(|f| {
+                let rank = prefixes
+                    .iter()
+                    .position(|p| f.path.starts_with(p.as_str()))
+                    .unwrap_or(prefixes.len());
+                (rank, f.file_size)
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    pub concurrency: usize,
+    pub order: DownloadOrder,
+    pub retry: RetryPolicy,
+    pub chunk_size: ChunkSize,
+    /// Codec the peer is asked to compress chunks with. The peer falls back to
+    /// plain chunks for files and chunks that do not compress.
+    pub compression: Compression,
+    /// Download limit shared with other downloads, unlimited if not set.
+    pub limiter: Option<Arc<Limiter>>,
+    /// Replace local files that already exist once the download checks out,
+    /// instead of failing it.
+    pub replace: bool,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            concurrency: 4,
+            order: DownloadOrder::SmallestFirst,
+            retry: RetryPolicy::default(),
+            chunk_size: ChunkSize::Peer,
+            compression: Compression::default(),
+            limiter: None,
+            replace: false,
+        }
+    }
+}
+
+/// What the GetFile requests of one download ask the serving peer for.
+#[derive(Debug)]
+struct Transfer {
+    tuner: std::sync::Mutex<ChunkTuner>,
+    compression: Compression,
+    limiter: Option<Arc<Limiter>>,
+    replace: bool,
+}
+
+impl Transfer {
+    fn new(options: &DownloadOptions) -> Self {
+        Transfer {
+            tuner: std::sync::Mutex::new(ChunkTuner::new(&options.chunk_size)),
+            compression: options.compression,
+            limiter: options.limiter.clone(),
+            replace: options.replace,
+        }
+    }
+
+    /// Builds a request and returns the chunk size it asks for.
+    fn request(
+        &self,
+        path: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> (GetFileRequest, Option<usize>) {
+        let chunk_size = self
+            .tuner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .size();
+        let request = GetFileRequest {
+            path: path.to_string(),
+            offset,
+            length,
+            chunk_size: chunk_size.map(|s| s as u32),
+            compression: pb::Compression::from(self.compression).into(),
+            password: None,
+        };
+        (request, chunk_size)
+    }
+
+    /// Waits until `n` more received bytes fit into the download limit.
+    async fn throttle(&self, n: usize) {
+        if let Some(l) = &self.limiter {
+            let wait = l.download(n as u64);
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+        }
+    }
+
+    fn record(&self, chunk_size: Option<usize>, bytes: u64, elapsed: Option<Duration>) {
+        if let (Some(size), Some(elapsed)) = (chunk_size, elapsed) {
+            self.tuner
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .record(size, bytes, elapsed);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DownloadReport {
+    pub successful: Vec<String>,
+    pub failed: Vec<String>,
+    /// Files that failed their integrity check, also listed in `failed`.
+    pub corrupted: Vec<String>,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl DownloadReport {
+    /// Aggregate bytes per second over all streams.
+    pub fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.bytes as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
 
 pub struct Client {
     share_path: String,
+    known_peers: Arc<KnownPeers>,
+    /// Presented to peers, so shares restricted to this node can be read.
+    identity: Option<Identity>,
+    /// Passwords for protected shares, by share name.
+    passwords: HashMap<String, String>,
+    /// Peers are asked as a member of this group.
+    group: Option<Arc<GroupKey>>,
 }
 
 impl Client {
+    /// A client that trusts the first key each peer presents for as long as
+    /// it lives.
+    ///
+    /// The keys are only kept in memory. They are neither checked against
+    /// nor added to the node's known peers, so a peer whose key changed
+    /// between two clients goes unnoticed. Use `with_known_peers` with the
+    /// node's `KnownPeers` wherever that matters.
     pub fn new(share_path: String) -> Client {
-        Client { share_path }
+        Self::with_known_peers(share_path, Arc::new(KnownPeers::default()))
+    }
+
+    pub fn with_known_peers(share_path: String, known_peers: Arc<KnownPeers>) -> Client {
+        Client {
+            share_path,
+            known_peers,
+            identity: None,
+            passwords: HashMap::new(),
+            group: None,
+        }
+    }
+
+    /// Membership is proven with the identity, so it has to be set as well.
+    pub fn with_group(mut self, group: Arc<GroupKey>) -> Client {
+        self.group = Some(group);
+        self
+    }
+
+    pub fn with_identity(mut self, identity: Identity) -> Client {
+        self.identity = Some(identity);
+        self
+    }
+
+    pub fn with_password(mut self, share: String, password: String) -> Client {
+        self.passwords.insert(share, password);
+        self
     }
 
     pub async fn get_all_files(
         self: Arc<Self>,
         addr: String,
         files: Vec<FileMetaData>,
-    ) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
+    ) -> Result<(Vec<String>, Vec<String>), LandohError> {
+        let report = self
+            .download(addr, files, DownloadOptions::default())
+            .await?;
+
+        Ok((report.successful, report.failed))
+    }
+
+    /// Fetches `files` from one peer over a single shared channel, running up
+    /// to `options.concurrency` GetFile streams at the same time.
+    pub async fn download(
+        self: Arc<Self>,
+        addr: String,
+        mut files: Vec<FileMetaData>,
+        options: DownloadOptions,
+    ) -> Result<DownloadReport, LandohError> {
+        let start = Instant::now();
+        let client = self.client(self.connect(&addr).await?)?;
+
+        options.order.sort(&mut files);
 
-        let mut success: Vec<String> = vec![];
-        let mut fail: Vec<String> = vec![];
+        let semaphore = Arc::new(Semaphore::new(options.concurrency.max(1)));
+        let transfer = Arc::new(Transfer::new(&options));
+        let mut tasks = JoinSet::new();
 
         for file in files {
-                let mut ok = false;
-                match self.get_file(addr.to_string(), &file).await {
-                    Ok(_) => {
-                        ok = true;
-                    }
-                    Err(err) => {
-                        error!("{:?}", err);
+            let permit = Arc::clone(&semaphore)
+                .acquire_owned()
+                .await
+                .map_err(|err| LandohError::Internal(err.to_string()))?;
+            let c = Arc::clone(&self);
+            let mut client = client.clone();
+            let retry = options.retry.clone();
+            let transfer = Arc::clone(&transfer);
+            info!("requesting '{}' from {}", &file.path, &addr);
+            tasks.spawn(async move {
+                let res = c
+                    .fetch_with_retry(&mut client, &file, &retry, &transfer)
+                    .await;
+                drop(permit);
+                (file, res)
+            });
+        }
+
+        let mut report = DownloadReport::default();
+        while let Some(res) = tasks.join_next().await {
+            let (file, res) = res?;
+            match res {
+                Ok(written) => {
+                    report.bytes += written;
+                    report.successful.push(file.path);
+                }
+                Err(err) => {
+                    error!("{}: {}", file.path, err);
+                    if err.is_integrity() {
+                        report.corrupted.push(file.path.clone());
                     }
-                };
-                if ok {
-                    success.push(file.path);
-                } else {
-                    fail.push(file.path);
+                    report.failed.push(file.path);
                 }
+            }
+        }
+        report.elapsed = start.elapsed();
+
+        info!(
+            "downloaded {} files ({} failed), {} bytes in {:?} ({:.0} bytes/s)",
+            report.successful.len(),
+            report.failed.len(),
+            report.bytes,
+            report.elapsed,
+            report.throughput()
+        );
+
+        Ok(report)
+    }
+
+    /// Fetches `dir` from several peers at once. Only peers whose listing
+    /// matches the first reachable peer (paths, sizes and hashes) take part.
+    /// Large files are split into ranges; a peer that cannot be reached any
+    /// more is dropped and its work goes back to the queue for the remaining
+    /// peers.
+    pub async fn swarm(
+        self: Arc<Self>,
+        dir: String,
+        addrs: Vec<String>,
+        options: DownloadOptions,
+    ) -> Result<DownloadReport, LandohError> {
+        let start = Instant::now();
+
+        let mut reference: Option<Vec<FileMetaData>> = None;
+        let mut peers: Vec<(String, PeerClient)> = vec![];
+
+        for addr in addrs {
+            let files = match self.get_directory(dir.clone(), addr.clone()).await {
+                Ok(f) => f,
+                Err(err) => {
+                    warn!("skipping peer {}: {}", addr, err);
+                    continue;
+                }
+            };
+
+            match &reference {
+                Some(r) if !same_content(r, &files) => {
+                    warn!("skipping peer {}: content of '{}' differs", addr, dir);
+                    continue;
+                }
+                Some(_) => {}
+                None => reference = Some(files),
+            }
+
+            match self.connect(&addr).await {
+                Ok(channel) => peers.push((addr, self.client(channel)?)),
+                Err(err) => warn!("skipping peer {}: {}", addr, err),
+            }
+        }
+
+        let mut files = match reference {
+            Some(f) if !peers.is_empty() => f,
+            _ => return Err(LandohError::Discovery(format!("no peer serves '{}'", dir))),
+        };
+
+        info!(
+            "swarming '{}' ({} files) from {} peers",
+            dir,
+            files.len(),
+            peers.len()
+        );
+
+        options.order.sort(&mut files);
+
+        let mut queue: VecDeque<Segment> = VecDeque::new();
+        let mut pending: HashMap<String, usize> = HashMap::new();
+
+        for file in files {
+            if peers.len() < 2 || file.file_size <= SWARM_RANGE_SIZE {
+                queue.push_back(Segment {
+                    file,
+                    offset: 0,
+                    length: None,
+                });
+                continue;
+            }
+
+            // Ranges land out of order, so an earlier partial download of this
+            // file cannot be resumed.
+            if let Ok(path) = local_path(&self.share_path, &file) {
+                let _ = fs::remove_file(part_path(&path));
+            }
+
+            let mut offset = 0;
+            while offset < file.file_size {
+                queue.push_back(Segment {
+                    file: file.clone(),
+                    offset,
+                    length: Some(SWARM_RANGE_SIZE),
+                });
+                *pending.entry(file.path.clone()).or_insert(0) += 1;
+                offset += SWARM_RANGE_SIZE;
+            }
+        }
+
+        let queue = Arc::new(Mutex::new(queue));
+        // Segments taken from the queue but not done yet. Only changed while
+        // the queue is locked, so an empty queue with nothing in flight means
+        // there is no work left.
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        // Wakes idle workers when a segment is done or back in the queue.
+        let done = Arc::new(Notify::new());
+        let pending = Arc::new(Mutex::new(pending));
+        let report = Arc::new(Mutex::new(DownloadReport::default()));
+        let transfer = Arc::new(Transfer::new(&options));
+        let mut tasks = JoinSet::new();
+
+        for (addr, client) in peers {
+            let alive = Arc::new(AtomicBool::new(true));
+            for _ in 0..options.concurrency.max(1) {
+                let c = Arc::clone(&self);
+                let addr = addr.clone();
+                let alive = Arc::clone(&alive);
+                let queue = Arc::clone(&queue);
+                let in_flight = Arc::clone(&in_flight);
+                let done = Arc::clone(&done);
+                let pending = Arc::clone(&pending);
+                let report = Arc::clone(&report);
+                let retry = options.retry.clone();
+                let transfer = Arc::clone(&transfer);
+                let mut client = client.clone();
+
+                tasks.spawn(async move {
+                    while alive.load(Ordering::SeqCst) {
+                        let mut q = queue.lock().await;
+                        let segment = match q.pop_front() {
+                            Some(s) => {
+                                in_flight.fetch_add(1, Ordering::SeqCst);
+                                s
+                            }
+                            None if in_flight.load(Ordering::SeqCst) == 0 => break,
+                            // A failing peer may still hand its segment back.
+                            None => {
+                                let notified = done.notified();
+                                drop(q);
+                                notified.await;
+                                continue;
+                            }
+                        };
+                        drop(q);
+
+                        let res = match segment.length {
+                            Some(length) => {
+                                c.fetch_range(
+                                    &mut client,
+                                    &segment.file,
+                                    segment.offset,
+                                    length,
+                                    &transfer,
+                                )
+                                .await
+                            }
+                            None => {
+                                c.fetch_file(&mut client, &segment.file, &retry, &transfer)
+                                    .await
+                            }
+                        };
+
+                        // Only a peer that cannot be reached any more is dropped.
+                        // Anything else, like corrupt data, a refusal of the peer
+                        // or a local error, fails the file instead of handing it
+                        // to another peer.
+                        let dropped = match res {
+                            Ok(written) => {
+                                c.segment_done(segment, written, &report, &pending, &retry)
+                                    .await;
+                                false
+                            }
+                            Err(err) if err.is_unreachable() => {
+                                warn!(
+                                    "dropping peer {} after '{}' failed: {}",
+                                    addr, segment.file.path, err
+                                );
+                                alive.store(false, Ordering::SeqCst);
+                                queue.lock().await.push_back(segment);
+                                true
+                            }
+                            Err(err) => {
+                                error!("{}: {}", segment.file.path, err);
+                                let mut r = report.lock().await;
+                                if err.is_integrity() && !r.corrupted.contains(&segment.file.path) {
+                                    r.corrupted.push(segment.file.path.clone());
+                                }
+                                if !r.failed.contains(&segment.file.path) {
+                                    r.failed.push(segment.file.path);
+                                }
+                                false
+                            }
+                        };
+
+                        {
+                            let _q = queue.lock().await;
+                            in_flight.fetch_sub(1, Ordering::SeqCst);
+                        }
+                        done.notify_waiters();
+                        if dropped {
+                            break;
+                        }
+                    }
+                });
+            }
+        }
+
+        while let Some(res) = tasks.join_next().await {
+            res?;
+        }
+
+        let mut report = Arc::try_unwrap(report)
+            .map_err(|_| LandohError::Internal("swarm workers still running".to_string()))?
+            .into_inner();
+
+        // Whatever is still queued could not be served by any peer.
+        let mut failed: HashSet<String> = HashSet::new();
+        for segment in queue.lock().await.drain(..) {
+            if failed.insert(segment.file.path.clone()) {
+                error!("{}: no peer left to serve it", segment.file.path);
+                report.failed.push(segment.file.path);
+            }
+        }
+        report.elapsed = start.elapsed();
+
+        info!(
+            "swarmed {} files ({} failed), {} bytes in {:?} ({:.0} bytes/s)",
+            report.successful.len(),
+            report.failed.len(),
+            report.bytes,
+            report.elapsed,
+            report.throughput()
+        );
+
+        Ok(report)
+    }
+
+    /// Compares a remote listing of `dir` with the local destination. Local files
+    /// missing from the listing are only scheduled for deletion if `delete` is set.
+    /// Hashing the local files blocks, so it runs on the blocking pool.
+    pub async fn plan_sync(
+        &self,
+        dir: &str,
+        files: Vec<FileMetaData>,
+        delete: bool,
+    ) -> Result<SyncPlan, LandohError> {
+        let destination = self.share_path.clone();
+        let dir = dir.to_string();
+        tokio::task::spawn_blocking(move || plan_sync(&destination, &dir, files, delete)).await?
+    }
+
+    /// Executes a plan created by `plan_sync`. Changed files are only replaced
+    /// once their new version has been downloaded and verified.
+    pub async fn sync(
+        self: Arc<Self>,
+        addr: String,
+        plan: SyncPlan,
+    ) -> Result<(Vec<String>, Vec<String>), LandohError> {
+        let mut files = plan.download;
+        files.extend(plan.replace);
+
+        for path in plan.delete {
+            match fs::remove_file(&path) {
+                Ok(_) => info!("deleted {:?}", path),
+                Err(err) => error!("failed to delete {:?}: {:?}", path, err),
+            }
         }
 
-        Ok((success, fail))
+        let options = DownloadOptions {
+            replace: true,
+            ..DownloadOptions::default()
+        };
+        let report = self.download(addr, files, options).await?;
+        Ok((report.successful, report.failed))
     }
 
-    pub async fn get_file(&self, addr: String, file: &FileMetaData) -> Result<(), Box<dyn Error>> {
+    pub async fn get_file(&self, addr: String, file: &FileMetaData) -> Result<(), LandohError> {
         info!("requesting '{}' from {}", &file.path, &addr);
-        let mut client = lan_doh_client::LanDohClient::connect(addr).await?;
+        let mut client = self.client(self.connect(&addr).await?)?;
 
-        let tmp_path = file.path.clone();
+        let transfer = Transfer::new(&DownloadOptions::default());
+        self.fetch_with_retry(&mut client, file, &RetryPolicy::default(), &transfer)
+            .await?;
+        Ok(())
+    }
 
-        let path = PathBuf::from(&self.share_path).join(&file.path);
+    async fn fetch_with_retry(
+        &self,
+        client: &mut PeerClient,
+        file: &FileMetaData,
+        retry: &RetryPolicy,
+        transfer: &Transfer,
+    ) -> Result<u64, LandohError> {
+        let mut attempt = 1;
+        loop {
+            match self.fetch_file(client, file, retry, transfer).await {
+                Ok(written) => return Ok(written),
+                Err(err) if attempt < retry.attempts && err.is_retryable() => {
+                    warn!(
+                        "attempt {}/{} for '{}' failed: {}",
+                        attempt, retry.attempts, file.path, err
+                    );
+                    tokio::time::sleep(retry.backoff * attempt).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
 
-        let mut dest_file = OpenOptions::new();
-        if path.exists() {
-            return Err(format!("file already exists: {:?}", path).into());
-        } else {
-            dest_file.write(true);
+    /// Downloads a single file over an existing connection and returns the
+    /// number of bytes received.
+    async fn fetch_file(
+        &self,
+        client: &mut PeerClient,
+        file: &FileMetaData,
+        retry: &RetryPolicy,
+        transfer: &Transfer,
+    ) -> Result<u64, LandohError> {
+        let path = local_path(&self.share_path, file)?;
+
+        if path.exists() && !transfer.replace {
+            return Err(already_exists(&path));
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let part_path = part_path(&path);
+        let mut context = Context::new(&SHA256);
+        let mut offset: u64 = 0;
+
+        if part_path.exists() {
+            if part_path.metadata()?.len() > file.file_size {
+                warn!("discarding stale partial download: {:?}", part_path);
+                fs::remove_file(&part_path)?;
+            } else {
+                let mut part = File::open(&part_path)?;
+                let mut buffer = vec![0; CHUNK_SIZE];
+                loop {
+                    let count = part.read(&mut buffer)?;
+                    if count == 0 {
+                        break;
+                    }
+                    context.update(&buffer[..count]);
+                    offset += count as u64;
+                }
+                info!("resuming '{}' at byte {}", &file.path, offset);
+            }
         }
-        let message = GetFileRequest { path: tmp_path };
+
+        let (mut message, chunk_size) = transfer.request(&file.path, offset, None);
+        message.password = self.password(&file.path);
 
         let request = tonic::Request::new(message);
 
-        let mut stream = client.get_file(request).await.unwrap().into_inner();
+        let mut stream = client.get_file(request).await?.into_inner();
 
         let mut written: u64 = 0;
-        let path = path.clone();
-        let parent = path.parent().unwrap();
-
-        fs::create_dir_all(&parent)?;
+        // Time spent waiting in the peer's queue says nothing about the chunk size.
+        let mut start: Option<Instant> = None;
 
-        let mut dest_file = dest_file.create(true).open(&path)?;
-        let mut context = Context::new(&SHA256);
+        let mut dest_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&part_path)?;
         let mut fileresp: FileMetaData = FileMetaData {
             path: "".to_string(),
             file_size: 0,
             hash: "".to_string(),
         };
         while let Some(resp) = stream.next().await {
-            match resp {
-                Ok(p) => {
-                    let r = match p.file_response {
-                        Some(r) => r,
-                        None => {
-                            break;
-                        }
-                    };
+            // A broken stream leaves the .part file in place so the next
+            // attempt can pick up where this one stopped.
+            let r = match resp?.file_response {
+                Some(r) => r,
+                None => {
+                    break;
+                }
+            };
 
-                    match r {
-                        FileResponse::Chunk(c) => {
-                            for i in &c {
-                                written += *i as u64;
-                            }
-                            context.update(&c.clone());
-                            dest_file.write_all(&c)?;
-                        }
-                        FileResponse::Meta(m) => {
-                            fileresp = m;
-                        }
-                    }
+            // Hashing the decompressed bytes keeps the check end to end.
+            let c = match r {
+                FileResponse::Chunk(c) => {
+                    transfer.throttle(c.len()).await;
+                    c
                 }
-                Err(err) => {
-                    error!("{:?}", err);
+                FileResponse::CompressedChunk(c) => {
+                    transfer.throttle(c.data.len()).await;
+                    decompress(c)?
                 }
-            }
+                FileResponse::Meta(m) => {
+                    fileresp = m;
+                    continue;
+                }
+                FileResponse::QueuePosition(p) => {
+                    info!("'{}' is queued at position {} by the peer", &file.path, p);
+                    continue;
+                }
+            };
+            start.get_or_insert_with(Instant::now);
+            written += c.len() as u64;
+            context.update(&c);
+            dest_file.write_all(&c)?;
         }
 
+        transfer.record(chunk_size, written, start.map(|s| s.elapsed()));
+
         let hash = HEXUPPER.encode(context.finish().as_ref());
 
         info!(
@@ -125,15 +788,216 @@ impl Client {
             written,
             fileresp.hash == hash
         );
-        Ok(())
+
+        if fileresp.hash != hash {
+            discard(&part_path, &path, retry.quarantine)?;
+            return Err(LandohError::Integrity {
+                path: file.path.clone(),
+                expected: fileresp.hash,
+                actual: hash,
+            });
+        }
+
+        fs::rename(&part_path, &path)?;
+        Ok(written)
+    }
+
+    /// Downloads `length` bytes at `offset` of a file into its .part file.
+    async fn fetch_range(
+        &self,
+        client: &mut PeerClient,
+        file: &FileMetaData,
+        offset: u64,
+        length: u64,
+        transfer: &Transfer,
+    ) -> Result<u64, LandohError> {
+        let path = local_path(&self.share_path, file)?;
+
+        if path.exists() && !transfer.replace {
+            return Err(already_exists(&path));
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut dest_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(part_path(&path))?;
+        dest_file.seek(SeekFrom::Start(offset))?;
+
+        let (mut message, chunk_size) = transfer.request(&file.path, offset, Some(length));
+        message.password = self.password(&file.path);
+
+        let mut stream = client
+            .get_file(tonic::Request::new(message))
+            .await?
+            .into_inner();
+
+        let mut written: u64 = 0;
+        let mut start: Option<Instant> = None;
+        while let Some(resp) = stream.next().await {
+            let c = match resp?.file_response {
+                Some(FileResponse::Chunk(c)) => {
+                    transfer.throttle(c.len()).await;
+                    c
+                }
+                Some(FileResponse::CompressedChunk(c)) => {
+                    transfer.throttle(c.data.len()).await;
+                    decompress(c)?
+                }
+                Some(FileResponse::Meta(m)) => {
+                    if m.hash != file.hash {
+                        return Err(LandohError::Integrity {
+                            path: file.path.clone(),
+                            expected: file.hash.clone(),
+                            actual: m.hash,
+                        });
+                    }
+                    continue;
+                }
+                Some(FileResponse::QueuePosition(p)) => {
+                    info!(
+                        "range {} of '{}' is queued at position {} by the peer",
+                        offset, &file.path, p
+                    );
+                    continue;
+                }
+                None => break,
+            };
+            start.get_or_insert_with(Instant::now);
+            written += c.len() as u64;
+            dest_file.write_all(&c)?;
+        }
+
+        transfer.record(chunk_size, written, start.map(|s| s.elapsed()));
+
+        let expected = length.min(file.file_size - offset);
+        if written != expected {
+            return Err(Status::data_loss(format!(
+                "short range for {:?} at {}: expected {} bytes, got {}",
+                file.path, offset, expected, written
+            ))
+            .into());
+        }
+
+        Ok(written)
+    }
+
+    /// Records a segment of a swarm download and finishes its file once all
+    /// of its ranges are there.
+    async fn segment_done(
+        &self,
+        segment: Segment,
+        written: u64,
+        report: &Mutex<DownloadReport>,
+        pending: &Mutex<HashMap<String, usize>>,
+        retry: &RetryPolicy,
+    ) {
+        report.lock().await.bytes += written;
+
+        if segment.length.is_none() {
+            report.lock().await.successful.push(segment.file.path);
+            return;
+        }
+
+        let last = {
+            let mut p = pending.lock().await;
+            let left = p.get_mut(&segment.file.path).map(|n| {
+                *n -= 1;
+                *n
+            });
+            if left == Some(0) {
+                p.remove(&segment.file.path);
+            }
+            left == Some(0)
+        };
+        if !last {
+            return;
+        }
+
+        // Hashing the whole file takes a while, the other workers go on
+        // meanwhile.
+        let res = self.finish_ranges(&segment.file, retry).await;
+        let mut r = report.lock().await;
+        match res {
+            Ok(_) => r.successful.push(segment.file.path),
+            Err(err) => {
+                error!("{}: {}", segment.file.path, err);
+                if err.is_integrity() {
+                    r.corrupted.push(segment.file.path.clone());
+                }
+                r.failed.push(segment.file.path);
+            }
+        }
     }
 
-    pub async fn list_directories(&self, addr: String) -> Result<(), Box<dyn Error>> {
-        let mut client = lan_doh_client::LanDohClient::connect(addr).await?;
+    /// Verifies a file assembled from ranges and moves it into place, on the
+    /// blocking pool.
+    async fn finish_ranges(
+        &self,
+        file: &FileMetaData,
+        retry: &RetryPolicy,
+    ) -> Result<(), LandohError> {
+        let path = local_path(&self.share_path, file)?;
+        let file = file.clone();
+        let quarantine = retry.quarantine;
+
+        tokio::task::spawn_blocking(move || {
+            let part_path = part_path(&path);
+            let hash = hash_file(&part_path)?;
+            if hash != file.hash {
+                discard(&part_path, &path, quarantine)?;
+                return Err(LandohError::Integrity {
+                    path: file.path,
+                    expected: file.hash,
+                    actual: hash,
+                });
+            }
+
+            fs::rename(&part_path, &path)?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Offers the file at `path` to a peer under its file name. Returns where
+    /// the peer stored it, relative to its destination, once the peer's owner
+    /// accepted it and the peer verified the hash.
+    pub async fn send_file(&self, addr: String, path: PathBuf) -> Result<String, LandohError> {
+        let name = match path.file_name() {
+            Some(n) if path.is_file() => n.to_string_lossy().to_string(),
+            _ => {
+                return Err(LandohError::InvalidRequest(format!(
+                    "not a file: {:?}",
+                    path
+                )))
+            }
+        };
+        let meta = FileMetaData {
+            file_size: path.metadata()?.len(),
+            hash: hash_file(&path)?,
+            path: name,
+        };
+
+        let mut client = self.client(self.connect(&addr).await?)?;
+        let (tx, rx) = mpsc::channel(SEND_BUFFER);
+        let reader = tokio::task::spawn_blocking(move || read_chunks(&path, meta, tx));
+        let response = client.send_file(ReceiverStream::new(rx)).await;
+
+        // A failed read ends the stream early, which the peer only sees as a
+        // short file.
+        reader.await??;
+        Ok(response?.into_inner().path)
+    }
+
+    pub async fn list_directories(&self, addr: String) -> Result<(), LandohError> {
+        let mut client = self.client(self.connect(&addr).await?)?;
         let _ = client
             .list_directories(tonic::Request::new(ListDirectoriesRequest {}))
-            .await
-            .unwrap()
+            .await?
             .into_inner();
 
         Ok(())
@@ -143,16 +1007,193 @@ impl Client {
         &self,
         name: String,
         addr: String,
-    ) -> Result<Vec<FileMetaData>, Box<dyn Error>> {
-        let mut client = lan_doh_client::LanDohClient::connect(addr).await?;
+    ) -> Result<Vec<FileMetaData>, LandohError> {
+        let mut client = self.client(self.connect(&addr).await?)?;
 
-        let message = GetDirectoryRequest { name: name };
+        let message = GetDirectoryRequest {
+            password: self.passwords.get(&name).cloned(),
+            name,
+        };
 
         let request = tonic::Request::new(message);
 
-        match client.get_directory(request).await {
-            Ok(resp) => Ok(resp.into_inner().files),
-            Err(err) => Err(Box::new(err))
+        Ok(client.get_directory(request).await?.into_inner().files)
+    }
+
+    async fn connect(&self, addr: &str) -> Result<Channel, LandohError> {
+        tls::connect(&self.known_peers, self.identity.as_ref(), addr).await
+    }
+
+    fn client(&self, channel: Channel) -> Result<PeerClient, LandohError> {
+        let token = match (&self.group, &self.identity) {
+            (Some(g), Some(i)) => Some(g.token(i.fingerprint())),
+            (Some(_), None) => {
+                return Err(LandohError::Config(
+                    "group members need an identity".to_string(),
+                ))
+            }
+            (None, _) => None,
+        };
+        Ok(lan_doh_client::LanDohClient::with_interceptor(
+            channel,
+            GroupToken::new(token)?,
+        ))
+    }
+
+    /// The password for the share `path` is in.
+    fn password(&self, path: &str) -> Option<String> {
+        let share = path.split(['/', '\\']).next()?;
+        self.passwords.get(share).cloned()
+    }
+}
+
+/// Where `file` of a peer's listing goes under `destination`. The path comes
+/// from the peer, so it may not lead out of the destination.
+fn local_path(destination: &str, file: &FileMetaData) -> Result<PathBuf, LandohError> {
+    received_path(Path::new(destination), &file.path)
+}
+
+fn plan_sync(
+    destination: &str,
+    dir: &str,
+    files: Vec<FileMetaData>,
+    delete: bool,
+) -> Result<SyncPlan, LandohError> {
+    let mut plan = SyncPlan::default();
+    let mut remote: HashSet<PathBuf> = HashSet::new();
+
+    for file in files {
+        let path = local_path(destination, &file)?;
+        remote.insert(path.clone());
+
+        if !path.exists() {
+            plan.download.push(file);
+            continue;
+        }
+
+        let m = path.metadata()?;
+        if m.is_file() && m.len() == file.file_size && hash_file(&path)? == file.hash {
+            plan.unchanged.push(file.path);
+        } else {
+            plan.replace.push(file);
+        }
+    }
+
+    let root = PathBuf::from(destination).join(dir);
+    if delete && root.is_dir() {
+        for e in WalkDir::new(&root) {
+            let e = e?;
+            if e.file_type().is_dir() || e.path().extension() == Some("part".as_ref()) {
+                continue;
+            }
+            if !remote.contains(e.path()) {
+                plan.delete.push(e.path().to_path_buf());
+            }
+        }
+    }
+
+    Ok(plan)
+}
+
+fn already_exists(path: &Path) -> LandohError {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("file already exists: {:?}", path),
+    )
+    .into()
+}
+
+/// Feeds a SendFile stream with `meta` followed by the file in chunks. This
+/// blocks on reads, so it has to run on the blocking pool.
+fn read_chunks(
+    path: &Path,
+    meta: FileMetaData,
+    tx: mpsc::Sender<SendFileRequest>,
+) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let send = |r: SendRequest| {
+        tx.blocking_send(SendFileRequest {
+            send_request: Some(r),
+        })
+    };
+    // The peer went away, there is no point in reading any further.
+    if send(SendRequest::Meta(meta)).is_err() {
+        return Ok(());
+    }
+
+    loop {
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            return Ok(());
         }
+        buffer.truncate(count);
+        if send(SendRequest::Chunk(buffer.into())).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Restores the original bytes of a compressed chunk.
+fn decompress(c: CompressedChunk) -> Result<Bytes, LandohError> {
+    let compression = pb::Compression::try_from(c.compression)
+        .map_err(|_| Status::data_loss(format!("unknown compression: {}", c.compression)))?;
+    let data = Compression::from(compression)
+        .decompress(&c.data, c.size as usize)
+        .map_err(|err| Status::data_loss(format!("failed to decompress chunk: {}", err)))?;
+    Ok(Bytes::from(data))
+}
+
+pub(crate) fn part_path(path: &Path) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(".part");
+    PathBuf::from(p)
+}
+
+/// Removes a corrupt download, or keeps it as `<file>.corrupt` for inspection.
+fn discard(part_path: &Path, path: &Path, quarantine: bool) -> io::Result<()> {
+    if !quarantine {
+        return fs::remove_file(part_path);
     }
-}
\ No newline at end of file
+    let mut p = path.as_os_str().to_owned();
+    p.push(".corrupt");
+    warn!("quarantining corrupt download as {:?}", p);
+    fs::rename(part_path, PathBuf::from(p))
+}
+
+fn same_content(a: &[FileMetaData], b: &[FileMetaData]) -> bool {
+    let key = |files: &[FileMetaData]| {
+        let mut k: Vec<(String, u64, String)> = files
+            .iter()
+            .map(|f| (f.path.clone(), f.file_size, f.hash.clone()))
+            .collect();
+        k.sort();
+        k
+    };
+    key(a) == key(b)
+}
+
+#[test]
+fn test_local_path() {
+    let file = |path: &str| FileMetaData {
+        path: path.to_string(),
+        file_size: 0,
+        hash: String::new(),
+    };
+    assert_eq!(
+        local_path("downloads", &file("share/a")).unwrap(),
+        Path::new("downloads").join("share").join("a")
+    );
+
+    // Peers may not write outside of the destination, not even through sync.
+    for bad in ["../x", "/etc/x"] {
+        assert!(matches!(
+            local_path("downloads", &file(bad)),
+            Err(LandohError::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            plan_sync("downloads", "share", vec![file(bad)], false),
+            Err(LandohError::InvalidRequest(_))
+        ));
+    }
+}
@@ -0,0 +1,152 @@
+use std::{fmt, io, path::Path, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::MAX_CHUNK_SIZE;
+use crate::pb;
+
+/// Formats that gain nothing from another round of compression.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "avi", "br", "bz2", "cab", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg",
+    "jpg", "lz4", "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "ogg", "png", "pptx", "rar", "tgz",
+    "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+/// Codec a client asks the serving peer to compress file chunks with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+impl Compression {
+    /// Compresses `data`, or returns `None` if that does not make it smaller.
+    pub(crate) fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        let packed = match self {
+            Compression::None => return None,
+            Compression::Lz4 => lz4_flex::block::compress(data),
+            Compression::Zstd => zstd::bulk::compress(data, 0).ok()?,
+        };
+        if packed.len() < data.len() {
+            Some(packed)
+        } else {
+            None
+        }
+    }
+
+    /// Restores a chunk of `size` bytes. `size` comes from the peer, so it is
+    /// checked against the largest chunk that may be requested.
+    pub(crate) fn decompress(self, data: &[u8], size: usize) -> io::Result<Vec<u8>> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        if size > MAX_CHUNK_SIZE {
+            return Err(invalid(format!("compressed chunk of {} bytes", size)));
+        }
+
+        let data = match self {
+            Compression::None => data.to_vec(),
+            Compression::Lz4 => {
+                lz4_flex::block::decompress(data, size).map_err(|err| invalid(err.to_string()))?
+            }
+            Compression::Zstd => zstd::bulk::decompress(data, size)?,
+        };
+        if data.len() != size {
+            return Err(invalid(format!(
+                "chunk decompressed to {} bytes instead of {}",
+                data.len(),
+                size
+            )));
+        }
+        Ok(data)
+    }
+}
+
+/// Whether the file at `path` is stored in an already compressed format.
+pub(crate) fn is_compressed(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => COMPRESSED_EXTENSIONS.contains(&ext.to_lowercase().as_str()),
+        None => false,
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("unknown compression: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl From<pb::Compression> for Compression {
+    fn from(c: pb::Compression) -> Self {
+        match c {
+            pb::Compression::None => Compression::None,
+            pb::Compression::Lz4 => Compression::Lz4,
+            pb::Compression::Zstd => Compression::Zstd,
+        }
+    }
+}
+
+impl From<Compression> for pb::Compression {
+    fn from(c: Compression) -> Self {
+        match c {
+            Compression::None => pb::Compression::None,
+            Compression::Lz4 => pb::Compression::Lz4,
+            Compression::Zstd => pb::Compression::Zstd,
+        }
+    }
+}
+
+#[test]
+fn test_compression_roundtrip() {
+    let text: Vec<u8> = b"2024-01-01 INFO serving LANdoh\n".repeat(2048);
+    let mut state: u32 = 0x9e3779b9;
+    let noise: Vec<u8> = (0..65536)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+
+    for codec in [Compression::Lz4, Compression::Zstd] {
+        let packed = codec.compress(&text).unwrap();
+        assert!(packed.len() < text.len() / 10);
+        assert_eq!(codec.decompress(&packed, text.len()).unwrap(), text);
+        assert!(codec.decompress(&packed, text.len() - 1).is_err());
+        assert!(codec.compress(&noise).is_none());
+    }
+
+    assert!(Compression::None.compress(&text).is_none());
+    assert!(Compression::Lz4
+        .decompress(&[0], MAX_CHUNK_SIZE + 1)
+        .is_err());
+
+    assert!(is_compressed(Path::new("share/backup.TAR.GZ")));
+    assert!(!is_compressed(Path::new("share/server.log")));
+    assert!(!is_compressed(Path::new("share/Makefile")));
+}
@@ -0,0 +1,290 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{mpsc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+/// How long a request waits for the owner to answer before it is denied.
+pub const APPROVAL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sent to the owner when a peer that was not approved yet asks for a share
+/// while `Config::ask_before_serving` is set.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ApprovalRequest {
+    /// Id of the peer, if it announced the certificate it connected with.
+    pub id: Option<String>,
+    /// Fingerprint of the peer's certificate, or its address if it presented
+    /// none. Answers refer to the peer by it.
+    pub peer: String,
+    pub ip: Option<String>,
+    pub share: String,
+}
+
+/// Sent to the owner when a peer offers to send a file. Answers refer to the
+/// offer by the peer and the file's path, size and hash.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct FileOffer {
+    /// Id of the peer, if it announced the certificate it connected with.
+    pub id: Option<String>,
+    /// Fingerprint of the peer's certificate, or its address if it presented
+    /// none.
+    pub peer: String,
+    pub ip: Option<String>,
+    pub path: String,
+    pub size: u64,
+    pub hash: String,
+}
+
+/// Peer, path, size and hash of an offer.
+type OfferKey = (String, String, u64, String);
+
+impl FileOffer {
+    fn key(&self) -> OfferKey {
+        (
+            self.peer.clone(),
+            self.path.clone(),
+            self.size,
+            self.hash.clone(),
+        )
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Approval {
+    Approved,
+    Denied,
+    /// Resolves once the owner answered.
+    Pending(oneshot::Receiver<bool>),
+}
+
+#[derive(Debug, Default)]
+struct State {
+    owner: Option<mpsc::Sender<ApprovalRequest>>,
+    /// Requests waiting for an answer, by peer.
+    waiting: HashMap<String, Vec<oneshot::Sender<bool>>>,
+    /// Peers approved until the server stops.
+    approved: HashSet<String>,
+}
+
+/// Requests waiting for the owner to approve them. A peer asking for many
+/// files at once is asked about only once.
+#[derive(Debug, Default)]
+pub struct Approvals {
+    state: Mutex<State>,
+}
+
+impl Approvals {
+    /// Approval requests are sent to the returned receiver from now on.
+    /// Without one, peers that have not been approved are denied.
+    pub fn subscribe(&self) -> mpsc::Receiver<ApprovalRequest> {
+        let (tx, rx) = mpsc::channel();
+        self.lock().owner = Some(tx);
+        rx
+    }
+
+    /// Answers the waiting requests of `peer`. An approved peer is served
+    /// without asking again until the server stops.
+    pub fn answer(&self, peer: &str, approve: bool) {
+        let mut s = self.lock();
+        if approve {
+            s.approved.insert(peer.to_string());
+        }
+        for tx in s.waiting.remove(peer).unwrap_or_default() {
+            let _ = tx.send(approve);
+        }
+    }
+
+    pub(crate) fn ask(&self, request: ApprovalRequest) -> Approval {
+        let mut guard = self.lock();
+        let s = &mut *guard;
+        if s.approved.contains(&request.peer) {
+            return Approval::Approved;
+        }
+
+        let waiting = s.waiting.entry(request.peer.clone()).or_default();
+        // Requests that timed out or were cancelled.
+        waiting.retain(|tx| !tx.is_closed());
+        if waiting.is_empty() {
+            let sent = match &s.owner {
+                Some(owner) => owner.send(request.clone()).is_ok(),
+                None => false,
+            };
+            if !sent {
+                warn!(
+                    "nobody to ask whether {} may access {}",
+                    request.peer, request.share
+                );
+                s.waiting.remove(&request.peer);
+                return Approval::Denied;
+            }
+        }
+
+        let (tx, rx) = oneshot::channel();
+        waiting.push(tx);
+        Approval::Pending(rx)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Offers {
+    owner: Option<mpsc::Sender<FileOffer>>,
+    /// Transfers waiting for an answer, by offer.
+    waiting: HashMap<OfferKey, Vec<oneshot::Sender<bool>>>,
+}
+
+/// Files offered by peers, waiting for the owner to accept them. Unlike
+/// `Approvals`, every offer is asked about and answered on its own, and
+/// accepting one says nothing about the next.
+#[derive(Debug, Default)]
+pub struct IncomingFiles {
+    state: Mutex<Offers>,
+}
+
+impl IncomingFiles {
+    /// Offers are sent to the returned receiver from now on. Without one,
+    /// every offer is declined.
+    pub fn subscribe(&self) -> mpsc::Receiver<FileOffer> {
+        let (tx, rx) = mpsc::channel();
+        self.lock().owner = Some(tx);
+        rx
+    }
+
+    /// Answers the transfers waiting for `offer`.
+    pub fn answer(&self, offer: &FileOffer, accept: bool) {
+        for tx in self.lock().waiting.remove(&offer.key()).unwrap_or_default() {
+            let _ = tx.send(accept);
+        }
+    }
+
+    /// Never `Approval::Approved`, the owner is asked about every offer.
+    /// Offers of the same file that are still waiting share one answer.
+    pub(crate) fn ask(&self, offer: FileOffer) -> Approval {
+        let mut guard = self.lock();
+        let s = &mut *guard;
+
+        let key = offer.key();
+        let waiting = s.waiting.entry(key.clone()).or_default();
+        // Transfers that timed out or were cancelled.
+        waiting.retain(|tx| !tx.is_closed());
+        if waiting.is_empty() {
+            let sent = match &s.owner {
+                Some(owner) => owner.send(offer.clone()).is_ok(),
+                None => false,
+            };
+            if !sent {
+                warn!(
+                    "nobody to ask whether {} may send {}",
+                    offer.peer, offer.path
+                );
+                s.waiting.remove(&key);
+                return Approval::Denied;
+            }
+        }
+
+        let (tx, rx) = oneshot::channel();
+        waiting.push(tx);
+        Approval::Pending(rx)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Offers> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+fn pending(approval: Approval) -> oneshot::Receiver<bool> {
+    match approval {
+        Approval::Pending(rx) => rx,
+        other => panic!("not pending: {:?}", other),
+    }
+}
+
+#[test]
+fn test_approvals() {
+    let approvals = Approvals::default();
+    let request = |peer: &str| ApprovalRequest {
+        id: None,
+        peer: peer.to_string(),
+        ip: None,
+        share: "share".to_string(),
+    };
+
+    // Nobody to ask.
+    assert!(matches!(approvals.ask(request("a")), Approval::Denied));
+
+    let owner = approvals.subscribe();
+    let mut first = pending(approvals.ask(request("a")));
+    let mut second = pending(approvals.ask(request("a")));
+    let mut other = pending(approvals.ask(request("b")));
+    assert_eq!(owner.try_recv().unwrap().peer, "a");
+    assert_eq!(owner.try_recv().unwrap().peer, "b");
+    assert!(owner.try_recv().is_err());
+
+    approvals.answer("a", true);
+    assert!(first.try_recv().unwrap());
+    assert!(second.try_recv().unwrap());
+    assert!(matches!(approvals.ask(request("a")), Approval::Approved));
+
+    approvals.answer("b", false);
+    assert!(!other.try_recv().unwrap());
+
+    // A denied peer is asked about again, also after its request timed out.
+    drop(pending(approvals.ask(request("b"))));
+    let _waiting = pending(approvals.ask(request("b")));
+    assert_eq!(owner.try_recv().unwrap().peer, "b");
+    assert_eq!(owner.try_recv().unwrap().peer, "b");
+}
+
+#[test]
+fn test_incoming_files() {
+    let incoming = IncomingFiles::default();
+    let offer = |peer: &str, path: &str| FileOffer {
+        id: None,
+        peer: peer.to_string(),
+        ip: None,
+        path: path.to_string(),
+        size: 3,
+        hash: "H".to_string(),
+    };
+
+    // Nobody to ask.
+    assert!(matches!(incoming.ask(offer("a", "x")), Approval::Denied));
+
+    let owner = incoming.subscribe();
+    let mut first = pending(incoming.ask(offer("a", "x")));
+    let mut again = pending(incoming.ask(offer("a", "x")));
+    let mut second = pending(incoming.ask(offer("a", "y")));
+    assert_eq!(owner.try_recv().unwrap().path, "x");
+    assert_eq!(owner.try_recv().unwrap().path, "y");
+    assert!(owner.try_recv().is_err());
+
+    // Accepting one file leaves the other offers of the peer waiting.
+    incoming.answer(&offer("a", "x"), true);
+    assert!(first.try_recv().unwrap());
+    assert!(again.try_recv().unwrap());
+    assert!(second.try_recv().is_err());
+
+    // Nothing is remembered, the same file is asked about again.
+    let _next = pending(incoming.ask(offer("a", "x")));
+    assert_eq!(owner.try_recv().unwrap().path, "x");
+
+    // A different file under the same path is a different offer.
+    incoming.answer(
+        &FileOffer {
+            hash: "other".to_string(),
+            ..offer("a", "y")
+        },
+        true,
+    );
+    assert!(second.try_recv().is_err());
+    incoming.answer(&offer("a", "y"), false);
+    assert!(!second.try_recv().unwrap());
+}
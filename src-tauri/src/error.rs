@@ -0,0 +1,223 @@
+use std::{error::Error, fmt, io};
+
+use tonic::Status;
+
+/// Everything that can go wrong in the library.
+#[derive(Debug)]
+pub enum LandohError {
+    /// The configuration could not be located, read, parsed or written.
+    Config(String),
+    /// Multicast discovery could not be set up or an announcement was unusable.
+    Discovery(String),
+    /// A connection or gRPC call to a peer failed.
+    Transport(Box<Status>),
+    /// Received data does not hash to what the peer announced.
+    Integrity {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+    /// Reading or writing local files failed.
+    Filesystem(io::Error),
+    /// A request or peer address is malformed or refers to something that is not shared.
+    InvalidRequest(String),
+    /// The peer is serving as many transfers as it allows and has no room to queue more.
+    Busy(String),
+    /// The peer is not allowed to access the named share.
+    AccessDenied(String),
+    /// The peer presented a different key than the one pinned for it.
+    KeyChanged {
+        peer: String,
+        expected: String,
+        actual: String,
+    },
+    /// A background task failed unexpectedly.
+    Internal(String),
+}
+
+impl LandohError {
+    pub fn is_integrity(&self) -> bool {
+        matches!(self, LandohError::Integrity { .. })
+    }
+
+    /// Whether trying the same transfer again has a chance of succeeding.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            LandohError::Integrity { .. } | LandohError::Busy(_) => true,
+            LandohError::Transport(s) => matches!(
+                s.code(),
+                tonic::Code::Unavailable
+                    | tonic::Code::ResourceExhausted
+                    | tonic::Code::Unknown
+                    | tonic::Code::Internal
+                    | tonic::Code::Aborted
+                    | tonic::Code::DataLoss
+                    | tonic::Code::Cancelled
+                    | tonic::Code::DeadlineExceeded
+            ),
+            _ => false,
+        }
+    }
+
+    /// Whether the peer could not be reached or the connection to it broke,
+    /// as opposed to the peer answering with an error.
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            LandohError::Transport(s) => matches!(
+                s.code(),
+                tonic::Code::Unavailable
+                    | tonic::Code::DeadlineExceeded
+                    | tonic::Code::Cancelled
+                    | tonic::Code::Unknown
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for LandohError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LandohError::Config(s) => write!(f, "config error: {}", s),
+            LandohError::Discovery(s) => write!(f, "discovery error: {}", s),
+            LandohError::Transport(s) => write!(f, "transport error: {}", s),
+            LandohError::Integrity {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "hash mismatch for {}: expected {}, got {}",
+                path, expected, actual
+            ),
+            LandohError::Filesystem(e) => write!(f, "filesystem error: {}", e),
+            LandohError::InvalidRequest(s) => write!(f, "invalid request: {}", s),
+            LandohError::Busy(s) => write!(f, "busy: {}", s),
+            LandohError::AccessDenied(s) => write!(f, "access to {} denied", s),
+            LandohError::KeyChanged {
+                peer,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "key of peer {} changed: expected {}, got {}. Forget the peer if it was reinstalled",
+                peer, expected, actual
+            ),
+            LandohError::Internal(s) => write!(f, "internal error: {}", s),
+        }
+    }
+}
+
+impl Error for LandohError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LandohError::Transport(s) => Some(s.as_ref()),
+            LandohError::Filesystem(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LandohError {
+    fn from(e: io::Error) -> Self {
+        LandohError::Filesystem(e)
+    }
+}
+
+impl From<walkdir::Error> for LandohError {
+    fn from(e: walkdir::Error) -> Self {
+        LandohError::Filesystem(e.into())
+    }
+}
+
+impl From<Status> for LandohError {
+    fn from(s: Status) -> Self {
+        LandohError::Transport(Box::new(s))
+    }
+}
+
+impl From<tonic::transport::Error> for LandohError {
+    fn from(e: tonic::transport::Error) -> Self {
+        // A changed key is found by the connector and must not end up as just
+        // another unavailable peer.
+        let mut source = e.source();
+        while let Some(s) = source {
+            if let Some(LandohError::KeyChanged {
+                peer,
+                expected,
+                actual,
+            }) = s.downcast_ref::<LandohError>()
+            {
+                return LandohError::KeyChanged {
+                    peer: peer.clone(),
+                    expected: expected.clone(),
+                    actual: actual.clone(),
+                };
+            }
+            source = s.source();
+        }
+        LandohError::Transport(Box::new(Status::unavailable(e.to_string())))
+    }
+}
+
+impl From<tokio::task::JoinError> for LandohError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        LandohError::Internal(e.to_string())
+    }
+}
+
+impl From<LandohError> for Status {
+    fn from(e: LandohError) -> Self {
+        match e {
+            LandohError::Transport(s) => *s,
+            LandohError::InvalidRequest(s) => Status::invalid_argument(s),
+            LandohError::Busy(_) => Status::resource_exhausted(e.to_string()),
+            LandohError::AccessDenied(_) => Status::permission_denied(e.to_string()),
+            LandohError::KeyChanged { .. } => Status::unauthenticated(e.to_string()),
+            LandohError::Integrity { .. } => Status::data_loss(e.to_string()),
+            LandohError::Filesystem(ref io) => match io.kind() {
+                io::ErrorKind::NotFound => Status::not_found(e.to_string()),
+                io::ErrorKind::PermissionDenied => Status::permission_denied(e.to_string()),
+                io::ErrorKind::AlreadyExists => Status::already_exists(e.to_string()),
+                _ => Status::internal(e.to_string()),
+            },
+            LandohError::Discovery(_) => Status::unavailable(e.to_string()),
+            LandohError::Config(_) => Status::failed_precondition(e.to_string()),
+            LandohError::Internal(_) => Status::internal(e.to_string()),
+        }
+    }
+}
+
+#[test]
+fn test_status_mapping() {
+    let not_found: Status =
+        LandohError::from(io::Error::new(io::ErrorKind::NotFound, "gone")).into();
+    assert_eq!(not_found.code(), tonic::Code::NotFound);
+
+    let exists: Status =
+        LandohError::from(io::Error::new(io::ErrorKind::AlreadyExists, "taken")).into();
+    assert_eq!(exists.code(), tonic::Code::AlreadyExists);
+
+    let invalid: Status = LandohError::InvalidRequest("../etc".to_string()).into();
+    assert_eq!(invalid.code(), tonic::Code::InvalidArgument);
+
+    let busy: Status = LandohError::Busy("queue full".to_string()).into();
+    assert_eq!(busy.code(), tonic::Code::ResourceExhausted);
+    let busy = LandohError::from(busy);
+    assert!(busy.is_retryable() && !busy.is_unreachable());
+    assert!(LandohError::from(Status::unavailable("reset")).is_unreachable());
+    assert!(!LandohError::from(Status::not_found("gone")).is_unreachable());
+
+    let denied: Status = LandohError::AccessDenied("share".to_string()).into();
+    assert_eq!(denied.code(), tonic::Code::PermissionDenied);
+    let denied = LandohError::from(denied);
+    assert!(!denied.is_retryable() && !denied.is_unreachable());
+
+    let corrupt: Status = LandohError::Integrity {
+        path: "a".to_string(),
+        expected: "1".to_string(),
+        actual: "2".to_string(),
+    }
+    .into();
+    assert_eq!(corrupt.code(), tonic::Code::DataLoss);
+}
@@ -0,0 +1,190 @@
+use std::{fmt, num::NonZeroU32, sync::Arc};
+
+use data_encoding::BASE64;
+use log::warn;
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    hkdf, hmac, pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use tonic::{metadata::MetadataValue, service::Interceptor, Request, Status};
+
+use crate::error::LandohError;
+use crate::server::requester;
+
+/// Metadata key gRPC requests carry the group token in.
+pub(crate) const TOKEN_METADATA: &str = "landoh-group";
+
+/// Every node has to derive the same key from a passphrase, so the salt is
+/// fixed. The iterations make guessing passphrases from sniffed packets slow.
+const SALT: &[u8] = b"landoh group";
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const ANNOUNCEMENT_INFO: &[u8] = b"landoh announcements";
+const TOKEN_INFO: &[u8] = b"landoh grpc";
+
+/// Keys derived from the passphrase of a private group. Members encrypt their
+/// announcements with it and prove membership on every gRPC call, so nodes
+/// only discover and talk to others of the same group.
+pub struct GroupKey {
+    announcements: LessSafeKey,
+    tokens: hmac::Key,
+}
+
+impl GroupKey {
+    pub fn new(passphrase: &str) -> Self {
+        let mut secret = [0u8; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+            SALT,
+            passphrase.as_bytes(),
+            &mut secret,
+        );
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(&secret);
+
+        // Both lengths are far below what HKDF can expand to.
+        let announcements: UnboundKey = prk
+            .expand(&[ANNOUNCEMENT_INFO], &CHACHA20_POLY1305)
+            .unwrap()
+            .into();
+        let tokens: hmac::Key = prk.expand(&[TOKEN_INFO], hmac::HMAC_SHA256).unwrap().into();
+
+        GroupKey {
+            announcements: LessSafeKey::new(announcements),
+            tokens,
+        }
+    }
+
+    /// Encrypts an announcement. The random nonce is sent in front of it.
+    pub(crate) fn seal(&self, data: &[u8]) -> Result<Vec<u8>, LandohError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| LandohError::Internal("failed to generate nonce".to_string()))?;
+
+        let mut sealed = data.to_vec();
+        self.announcements
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(ANNOUNCEMENT_INFO),
+                &mut sealed,
+            )
+            .map_err(|_| LandohError::Internal("failed to encrypt announcement".to_string()))?;
+
+        let mut packet = nonce.to_vec();
+        packet.append(&mut sealed);
+        Ok(packet)
+    }
+
+    /// Decrypts an announcement, failing for those of other groups.
+    pub(crate) fn open(&self, packet: &[u8]) -> Result<Vec<u8>, LandohError> {
+        let outside =
+            || LandohError::Discovery("rejected announcement from outside the group".to_string());
+        if packet.len() < NONCE_LEN + aead::MAX_TAG_LEN {
+            return Err(outside());
+        }
+
+        let (nonce, sealed) = packet.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| outside())?;
+        let mut data = sealed.to_vec();
+        let len = self
+            .announcements
+            .open_in_place(nonce, Aad::from(ANNOUNCEMENT_INFO), &mut data)
+            .map_err(|_| outside())?
+            .len();
+        data.truncate(len);
+        Ok(data)
+    }
+
+    /// Proves membership for the node with the certificate `fingerprint`.
+    /// Bound to the certificate, a token is of no use to anyone without its
+    /// key.
+    pub(crate) fn token(&self, fingerprint: &str) -> String {
+        BASE64.encode(hmac::sign(&self.tokens, fingerprint.as_bytes()).as_ref())
+    }
+
+    pub(crate) fn verify(&self, fingerprint: &str, token: &str) -> bool {
+        match BASE64.decode(token.as_bytes()) {
+            Ok(tag) => hmac::verify(&self.tokens, fingerprint.as_bytes(), &tag).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+impl fmt::Debug for GroupKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GroupKey").finish_non_exhaustive()
+    }
+}
+
+/// Adds the group token to every request of a client.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct GroupToken(Option<MetadataValue<tonic::metadata::Ascii>>);
+
+impl GroupToken {
+    pub(crate) fn new(token: Option<String>) -> Result<Self, LandohError> {
+        match token {
+            Some(t) => MetadataValue::try_from(t)
+                .map(|v| GroupToken(Some(v)))
+                .map_err(|err| LandohError::Internal(err.to_string())),
+            None => Ok(GroupToken(None)),
+        }
+    }
+}
+
+impl Interceptor for GroupToken {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(t) = &self.0 {
+            request.metadata_mut().insert(TOKEN_METADATA, t.clone());
+        }
+        Ok(request)
+    }
+}
+
+/// Refuses requests without a token that matches the client certificate they
+/// were sent with, if the server is in a group.
+#[derive(Clone, Debug)]
+pub(crate) struct Membership(pub(crate) Option<Arc<GroupKey>>);
+
+impl Interceptor for Membership {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let group = match &self.0 {
+            Some(g) => g,
+            None => return Ok(request),
+        };
+
+        let requester = requester(&request);
+        let token = request
+            .metadata()
+            .get(TOKEN_METADATA)
+            .and_then(|t| t.to_str().ok());
+        match (&requester.fingerprint, token) {
+            (Some(fp), Some(t)) if group.verify(fp, t) => Ok(request),
+            _ => {
+                warn!("refusing request from {:?} outside the group", requester.ip);
+                Err(Status::unauthenticated("not a member of this group"))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_group_key() {
+    let key = GroupKey::new("team blue");
+    let same = GroupKey::new("team blue");
+    let other = GroupKey::new("team red");
+
+    let packet = key.seal(b"announcement").unwrap();
+    assert_eq!(same.open(&packet).unwrap(), b"announcement");
+    assert!(other.open(&packet).is_err());
+    assert!(same.open(b"{\"source\": \"plain\"}").is_err());
+    let mut tampered = packet.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(same.open(&tampered).is_err());
+
+    let token = key.token("AB12");
+    assert!(same.verify("AB12", &token));
+    assert!(!same.verify("CD34", &token));
+    assert!(!other.verify("AB12", &token));
+    assert!(!same.verify("AB12", "not base64"));
+}
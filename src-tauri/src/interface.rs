@@ -0,0 +1,161 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+use if_addrs::IfAddr;
+use serde::{Deserialize, Serialize};
+
+/// Which network interfaces discovery runs on, by name.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct InterfaceFilter {
+    /// Only these, or all of them if empty.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Never these, e.g. the adapters of a VPN.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+impl InterfaceFilter {
+    pub fn allows(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|i| i == name))
+            && !self.exclude.iter().any(|e| e == name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+}
+
+/// A network interface with the addresses this node has on it.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Interface {
+    pub(crate) name: String,
+    /// Identifies the interface for IPv6 multicast and zones.
+    pub(crate) index: u32,
+    /// Addresses with their netmasks.
+    pub(crate) addrs: Vec<(IpAddr, IpAddr)>,
+}
+
+impl Interface {
+    /// Identifies the interface for IPv4 multicast.
+    pub(crate) fn ipv4(&self) -> Option<Ipv4Addr> {
+        self.addrs.iter().find_map(|(ip, _)| match ip {
+            IpAddr::V4(v4) => Some(*v4),
+            _ => None,
+        })
+    }
+
+    pub(crate) fn has_ipv6(&self) -> bool {
+        self.addrs.iter().any(|(ip, _)| ip.is_ipv6())
+    }
+
+    /// Whether `ip` is on one of the networks of the interface.
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.addrs
+            .iter()
+            .any(|(addr, mask)| match (ip, addr, mask) {
+                (IpAddr::V4(ip), IpAddr::V4(addr), IpAddr::V4(mask)) => {
+                    let mask = u32::from(*mask);
+                    u32::from(ip) & mask == u32::from(*addr) & mask
+                }
+                (IpAddr::V6(ip), IpAddr::V6(addr), IpAddr::V6(mask)) => {
+                    let mask = u128::from(*mask);
+                    u128::from(ip) & mask == u128::from(*addr) & mask
+                }
+                _ => false,
+            })
+    }
+}
+
+/// The interfaces `filter` allows. Loopback is left out, other nodes on this
+/// host get announcements through the multicast loop of the real interfaces.
+pub(crate) fn list(filter: &InterfaceFilter) -> io::Result<Vec<Interface>> {
+    let mut found: Vec<Interface> = vec![];
+    for i in if_addrs::get_if_addrs()? {
+        if i.is_loopback() || !filter.allows(&i.name) {
+            continue;
+        }
+        let addr = match &i.addr {
+            IfAddr::V4(a) => (IpAddr::V4(a.ip), IpAddr::V4(a.netmask)),
+            IfAddr::V6(a) => (IpAddr::V6(a.ip), IpAddr::V6(a.netmask)),
+        };
+        match found.iter_mut().find(|f| f.name == i.name) {
+            Some(f) => f.addrs.push(addr),
+            None => found.push(Interface {
+                name: i.name,
+                index: i.index.unwrap_or(0),
+                addrs: vec![addr],
+            }),
+        }
+    }
+    Ok(found)
+}
+
+/// The interface a packet from `addr` came in on. Link-local IPv6 senders
+/// name it by their zone, all others are looked up by network.
+pub(crate) fn seen_on(interfaces: &[Interface], addr: SocketAddr) -> Option<String> {
+    let found = match addr {
+        SocketAddr::V6(a) if a.scope_id() != 0 => {
+            interfaces.iter().find(|i| i.index == a.scope_id())
+        }
+        a => interfaces.iter().find(|i| i.contains(a.ip())),
+    };
+    found.map(|i| i.name.clone())
+}
+
+/// The IPv4 address this node has on the interface `name`, to reach peers
+/// seen there through the same interface.
+pub fn ipv4_of(name: &str) -> Option<Ipv4Addr> {
+    let filter = InterfaceFilter {
+        include: vec![name.to_string()],
+        exclude: vec![],
+    };
+    list(&filter).ok()?.iter().find_map(Interface::ipv4)
+}
+
+#[test]
+fn test_interfaces() {
+    let filter = InterfaceFilter {
+        include: vec![],
+        exclude: vec!["tun0".to_string()],
+    };
+    assert!(filter.allows("eth0"));
+    assert!(!filter.allows("tun0"));
+    let only = InterfaceFilter {
+        include: vec!["eth0".to_string()],
+        exclude: vec![],
+    };
+    assert!(only.allows("eth0"));
+    assert!(!only.allows("wlan0"));
+
+    let interfaces = vec![
+        Interface {
+            name: "eth0".to_string(),
+            index: 2,
+            addrs: vec![(
+                "192.168.1.10".parse().unwrap(),
+                "255.255.255.0".parse().unwrap(),
+            )],
+        },
+        Interface {
+            name: "wlan0".to_string(),
+            index: 3,
+            addrs: vec![
+                ("10.0.0.5".parse().unwrap(), "255.0.0.0".parse().unwrap()),
+                (
+                    "fe80::5".parse().unwrap(),
+                    "ffff:ffff:ffff:ffff::".parse().unwrap(),
+                ),
+            ],
+        },
+    ];
+    let seen = |addr: &str| seen_on(&interfaces, addr.parse().unwrap());
+    assert_eq!(seen("192.168.1.20:7645").as_deref(), Some("eth0"));
+    assert_eq!(seen("10.1.2.3:7645").as_deref(), Some("wlan0"));
+    assert_eq!(seen("[fe80::9%3]:7645").as_deref(), Some("wlan0"));
+    assert_eq!(seen("172.16.0.1:7645"), None);
+    assert_eq!(interfaces[1].ipv4(), Some(Ipv4Addr::new(10, 0, 0, 5)));
+    assert!(!interfaces[0].has_ipv6());
+}
@@ -1,10 +1,25 @@
+mod access;
 pub mod app;
+mod cache;
+mod chunk;
+mod compression;
+mod consent;
+mod group;
+mod interface;
 pub mod client;
+pub mod error;
+mod limit;
 mod model;
 pub mod multicast;
 mod pb;
+mod queue;
 mod server;
+mod signing;
 pub mod source;
+mod tls;
+mod wire;
+
+pub use error::LandohError;
 
 pub fn shorten_path(name: String, path: String) -> String {
     let start: usize;
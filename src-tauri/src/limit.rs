@@ -0,0 +1,256 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Bandwidth limits in bytes per second, 0 meaning unlimited.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct RateLimits {
+    /// Everything this peer serves, across all other peers.
+    pub upload: u64,
+    /// What a single other peer may download from this one.
+    pub peer_upload: u64,
+    /// Everything this peer downloads.
+    pub download: u64,
+}
+
+/// A token bucket that lets callers go into debt: taking more tokens than are
+/// available succeeds, and the returned delay is how long the caller has to
+/// wait until the debt is paid off. Concurrent streams sharing a bucket thus
+/// add up to its rate.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate,
+            tokens: burst(rate),
+            last: Instant::now(),
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: u64) {
+        self.set_rate_at(rate, Instant::now());
+    }
+
+    /// Takes `n` tokens and returns how long to wait before using them.
+    pub fn take(&mut self, n: u64) -> Duration {
+        self.take_at(n, Instant::now())
+    }
+
+    fn set_rate_at(&mut self, rate: u64, now: Instant) {
+        if self.rate == 0 {
+            self.tokens = burst(rate);
+            self.last = self.last.max(now);
+        } else {
+            self.refill(now);
+            self.tokens = self.tokens.min(burst(rate));
+        }
+        self.rate = rate;
+    }
+
+    fn take_at(&mut self, n: u64, now: Instant) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        self.refill(now);
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        let paid = self.last + Duration::from_secs_f64(-self.tokens / self.rate as f64);
+        paid.saturating_duration_since(now)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(burst(self.rate));
+        self.last = self.last.max(now);
+    }
+}
+
+/// A quarter of a second worth of data may be sent without waiting.
+fn burst(rate: u64) -> f64 {
+    rate as f64 / 4.0
+}
+
+type Bucket = Arc<Mutex<TokenBucket>>;
+
+fn take(bucket: &Bucket, n: u64) -> Duration {
+    bucket
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take(n)
+}
+
+fn set_rate(bucket: &Bucket, rate: u64) {
+    bucket
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .set_rate(rate);
+}
+
+/// The buckets enforcing `RateLimits`, shared by the server and all downloads
+/// so that changed limits apply to transfers that are already running.
+#[derive(Debug)]
+pub struct Limiter {
+    upload: Bucket,
+    download: Bucket,
+    peer_upload: Mutex<(u64, HashMap<IpAddr, Bucket>)>,
+}
+
+impl Limiter {
+    pub fn new(limits: &RateLimits) -> Self {
+        Limiter {
+            upload: Arc::new(Mutex::new(TokenBucket::new(limits.upload))),
+            download: Arc::new(Mutex::new(TokenBucket::new(limits.download))),
+            peer_upload: Mutex::new((limits.peer_upload, HashMap::new())),
+        }
+    }
+
+    pub fn set(&self, limits: &RateLimits) {
+        set_rate(&self.upload, limits.upload);
+        set_rate(&self.download, limits.download);
+
+        let mut peers = self
+            .peer_upload
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        peers.0 = limits.peer_upload;
+        for bucket in peers.1.values() {
+            set_rate(bucket, limits.peer_upload);
+        }
+    }
+
+    /// The limits of a single upload to `peer`.
+    pub(crate) fn upload(&self, peer: Option<IpAddr>) -> Upload {
+        let peer = peer.map(|ip| {
+            let mut peers = self
+                .peer_upload
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let rate = peers.0;
+            // Peers without a running upload do not need their bucket anymore.
+            peers.1.retain(|_, b| Arc::strong_count(b) > 1);
+            Arc::clone(
+                peers
+                    .1
+                    .entry(ip)
+                    .or_insert_with(|| Arc::new(Mutex::new(TokenBucket::new(rate)))),
+            )
+        });
+
+        Upload {
+            global: Arc::clone(&self.upload),
+            peer,
+        }
+    }
+
+    /// Returns how long to wait before using `n` more downloaded bytes.
+    pub(crate) fn download(&self, n: u64) -> Duration {
+        take(&self.download, n)
+    }
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Limiter::new(&RateLimits::default())
+    }
+}
+
+/// The buckets a single upload has to pass.
+#[derive(Debug)]
+pub(crate) struct Upload {
+    global: Bucket,
+    peer: Option<Bucket>,
+}
+
+impl Upload {
+    /// Returns how long to wait before sending `n` more bytes.
+    pub(crate) fn take(&self, n: u64) -> Duration {
+        let global = take(&self.global, n);
+        match &self.peer {
+            Some(p) => global.max(take(p, n)),
+            None => global,
+        }
+    }
+}
+
+#[test]
+fn test_token_bucket_rate() {
+    let rate = 1024 * 1024;
+    let chunk = 64 * 1024;
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(rate);
+    bucket.last = start;
+
+    // Two streams sharing the bucket, each sending as soon as it may.
+    let mut now = [start, start];
+    let mut sent = 0;
+    while sent < 10 * rate {
+        let i = if now[0] <= now[1] { 0 } else { 1 };
+        now[i] += bucket.take_at(chunk, now[i]);
+        sent += chunk;
+    }
+
+    let elapsed = now[0].max(now[1]).duration_since(start).as_secs_f64();
+    let expected = (sent as f64 - burst(rate)) / rate as f64;
+    assert!(
+        (elapsed - expected).abs() < expected * 0.01,
+        "sent {} bytes in {}s, expected {}s",
+        sent,
+        elapsed,
+        expected
+    );
+}
+
+#[test]
+fn test_token_bucket_set_rate() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(0);
+    bucket.last = start;
+    assert_eq!(bucket.take_at(u64::MAX / 2, start), Duration::ZERO);
+
+    bucket.set_rate_at(1000, start);
+    assert_eq!(bucket.take_at(250, start), Duration::ZERO);
+    assert_eq!(bucket.take_at(500, start), Duration::from_millis(500));
+
+    // Lowering the rate slows down paying off the debt.
+    bucket.set_rate_at(100, start);
+    assert_eq!(bucket.take_at(0, start), Duration::from_secs(5));
+
+    bucket.set_rate_at(0, start);
+    assert_eq!(bucket.take_at(1 << 30, start), Duration::ZERO);
+}
+
+#[test]
+fn test_limiter_peer_upload() {
+    let limiter = Limiter::new(&RateLimits {
+        upload: 0,
+        peer_upload: 1000,
+        download: 0,
+    });
+    let a: IpAddr = "192.168.0.2".parse().unwrap();
+    let b: IpAddr = "192.168.0.3".parse().unwrap();
+
+    let first = limiter.upload(Some(a));
+    let second = limiter.upload(Some(a));
+    let other = limiter.upload(Some(b));
+
+    assert_eq!(first.take(250), Duration::ZERO);
+    assert!(second.take(500) > Duration::from_millis(400));
+    assert_eq!(other.take(250), Duration::ZERO);
+
+    limiter.set(&RateLimits::default());
+    assert_eq!(first.take(1 << 30), Duration::ZERO);
+    assert_eq!(limiter.download(1 << 30), Duration::ZERO);
+}
@@ -3,15 +3,19 @@
 
 use std::path::PathBuf;
 use std::process::Command;
-use std::{net::SocketAddr, sync::Arc, thread, time::Duration};
+use std::{net::SocketAddr, sync::mpsc, sync::Arc, thread, time::Duration};
 
 use chrono::{DateTime, Utc};
 
-use landoh::client::Client;
+use landoh::client::{ChunkSize, Client, Compression, DownloadOptions};
 
-use landoh::app::{App, Config};
+use landoh::app::{
+    identity, known_peers, signing_key, Access, App, Config, FileOffer, GroupKey,
+    InterfaceFilter, KnownPeers, Limiter, RateLimits,
+};
+use landoh::LandohError;
 
-use landoh::source::Source;
+use landoh::source::{Source, DEFAULT_PORT};
 use log::{info, warn};
 use serde::Serialize;
 use tauri::{Manager, Window};
@@ -24,21 +28,32 @@ struct Payload {
     id: String,
     nickname: String,
     ip: Option<String>,
-    timestamp: DateTime<Utc>
+    timestamp: DateTime<Utc>,
+    interface: Option<String>,
 }
 
 impl Payload {
-    pub fn new(name: String, id: String, nickname: String, ip: Option<String>, timestamp: DateTime<Utc>) -> Self {
+    pub fn new(name: String, id: String, nickname: String, ip: Option<String>, timestamp: DateTime<Utc>, interface: Option<String>) -> Self {
         Payload{
             name,
             id, 
             nickname,
             ip,
             timestamp,
+            interface,
         }
     }
 }
 
+#[derive(Serialize, Clone)]
+struct FilePayload {
+    id: String,
+    dir: String,
+    successful: Vec<String>,
+    failed: Vec<String>,
+    corrupted: Vec<String>,
+}
+
 #[tauri::command]
 async fn open_dir(path: String) -> Result<(),()> {
     let _ = Command::new("explorer").arg(path).spawn();
@@ -46,9 +61,117 @@ async fn open_dir(path: String) -> Result<(),()> {
 }
 
 #[tauri::command]
-async fn serve(state: tauri::State<'_, Arc<tokio::sync::Mutex<App>>>) -> Result<(), ()> {
+async fn serve(
+    state: tauri::State<'_, Arc<tokio::sync::Mutex<App>>>,
+    window: Window,
+) -> Result<(), ()> {
     let a = Arc::clone(&state);
-        a.lock().await.serve().await;
+
+    // Peers waiting for approval and files offered by peers are shown to the
+    // owner, who answers with `answer_approval` and `answer_incoming_file`.
+    // This has to be in place before the first request comes in.
+    let (approvals, incoming) = {
+        let app = a.lock().await;
+        (app.approvals.subscribe(), app.incoming.subscribe())
+    };
+    forward(approvals, window.clone(), "approval");
+    forward(incoming, window, "incoming_file");
+
+    a.lock().await.serve().await;
+    Ok(())
+}
+
+/// Emits every request from `rx` as `event` until the server stops.
+fn forward<T>(rx: mpsc::Receiver<T>, window: Window, event: &'static str)
+where
+    T: Serialize + Clone + std::fmt::Debug + Send + 'static,
+{
+    thread::spawn(move || {
+        while let Ok(request) = rx.recv() {
+            info!("asking the owner: {:?}", request);
+            if let Err(err) = window.emit_all(event, request) {
+                warn!("Error emitting message: {:?}", err);
+            }
+        }
+    });
+}
+
+#[tauri::command]
+async fn answer_incoming_file(
+    offer: FileOffer,
+    accept: bool,
+    state: tauri::State<'_, Arc<tokio::sync::Mutex<App>>>,
+) -> Result<(), ()> {
+    state.lock().await.incoming.answer(&offer, accept);
+    Ok(())
+}
+
+#[tauri::command]
+async fn send_file(
+    id: String,
+    path: String,
+    state: tauri::State<'_, Arc<tokio::sync::Mutex<App>>>,
+) -> Result<String, String> {
+    let (source, known_peers, identity, group) = {
+        let app = state.lock().await;
+        let source = app
+            .sources
+            .lock()
+            .await
+            .iter()
+            .find(|s| s.id == id)
+            .cloned();
+        (
+            source,
+            Arc::clone(&app.known_peers),
+            app.identity.clone(),
+            app.group.clone(),
+        )
+    };
+    let source = match source {
+        Some(s) => s,
+        None => return Err(format!("unknown peer: {}", id)),
+    };
+
+    let addr = peer_url(Some(&source));
+    announce_source(&known_peers, &addr, &source);
+
+    let mut c = Client::with_known_peers(".".to_string(), known_peers).with_identity(identity);
+    if let Some(g) = group {
+        c = c.with_group(g);
+    }
+
+    info!("SENDING: {} to {:?}", path, addr);
+    c.send_file(addr, PathBuf::from(&path)).await.map_err(|err| {
+        warn!("failed to send {}: {}", path, err);
+        err.to_string()
+    })
+}
+
+#[tauri::command]
+async fn answer_approval(
+    peer: String,
+    approve: bool,
+    remember: bool,
+    state: tauri::State<'_, Arc<tokio::sync::Mutex<App>>>,
+) -> Result<(), ()> {
+    let app = state.lock().await;
+    app.answer_approval(peer, approve, remember).await;
+    if remember {
+        let _ = app.save_config().await;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn update_ask_before_serving(
+    enabled: bool,
+    state: tauri::State<'_, Arc<tokio::sync::Mutex<App>>>,
+) -> Result<(), ()> {
+    let app = state.lock().await;
+    app.config.lock().await.ask_before_serving = enabled;
+    info!("ask before serving: {}", enabled);
+    let _ = app.save_config().await;
     Ok(())
 }
 
@@ -66,7 +189,7 @@ async fn update_nickname(
 ) -> Result<(), ()> {
     info!("updated nickname to: {}", &nickname);
     state.lock().await.config.lock().await.nickname = nickname;
-    state.lock().await.save_config().await;
+    let _ = state.lock().await.save_config().await;
     Ok(())
 }
 
@@ -77,23 +200,43 @@ async fn update_destination(
 ) -> Result<(), ()> {
     info!("updated destination to: {}", &destination);
     state.lock().await.config.lock().await.destination = PathBuf::from(destination);
-    state.lock().await.save_config().await;
+    let _ = state.lock().await.save_config().await;
+    Ok(())
+}
+
+#[tauri::command]
+async fn update_limits(
+    upload: u64,
+    peer_upload: u64,
+    download: u64,
+    state: tauri::State<'_, Arc<tokio::sync::Mutex<App>>>,
+) -> Result<(), ()> {
+    let limits = RateLimits {
+        upload,
+        peer_upload,
+        download,
+    };
+    info!("updated bandwidth limits to: {:?}", &limits);
+    state.lock().await.set_limits(limits).await;
+    let _ = state.lock().await.save_config().await;
     Ok(())
 }
 
 #[tauri::command]
 async fn add_shared_dir(
     path: String,
+    name: Option<String>,
     state: tauri::State<'_, Arc<tokio::sync::Mutex<App>>>,
 ) -> Result<(), ()> {
-    let _ = state
+    let name = name.unwrap_or(path.clone());
+    let res = state
         .lock()
         .await
-        .add_shared_dir(path.clone(), vec![path])
+        .add_shared_dir(name, vec![path])
         .await;
 
     let _ = state.lock().await.save_config().await;
-    Ok(())
+    res.map_err(|err| warn!("failed to share directory: {}", err))
 }
 
 #[tauri::command]
@@ -107,18 +250,73 @@ async fn remove_shared_dir(
     Ok(())
 }
 
+#[tauri::command]
+async fn remove_shared_path(
+    name: String,
+    path: String,
+    state: tauri::State<'_, Arc<tokio::sync::Mutex<App>>>,
+) -> Result<(), ()> {
+    state.lock().await.remove_shared_path(name, path).await;
+    let _ = state.lock().await.save_config().await;
+
+    Ok(())
+}
+
+/// Drops the key pinned for a peer, so that it is trusted again after it was
+/// reinstalled.
+#[tauri::command]
+async fn forget_peer(
+    id: String,
+    state: tauri::State<'_, Arc<tokio::sync::Mutex<App>>>,
+) -> Result<(), ()> {
+    if let Err(err) = state.lock().await.known_peers.forget(&id) {
+        warn!("failed to forget peer {}: {}", id, err);
+    }
+    Ok(())
+}
+
+/// Sets who may see and download a share. `mode` is one of `public`,
+/// `password` or `restricted`.
+#[tauri::command]
+async fn set_share_access(
+    name: String,
+    mode: String,
+    password: Option<String>,
+    peers: Vec<String>,
+    networks: Vec<String>,
+    state: tauri::State<'_, Arc<tokio::sync::Mutex<App>>>,
+) -> Result<(), String> {
+    let access = match (mode.as_str(), password) {
+        ("public", _) => Access::Public,
+        ("password", Some(p)) if !p.is_empty() => {
+            Access::password(&p).map_err(|err| err.to_string())?
+        }
+        ("restricted", _) => Access::Restricted { peers, networks },
+        _ => return Err(format!("invalid access mode: {}", mode)),
+    };
+
+    let app = state.lock().await;
+    if let Err(err) = app.set_access(name.clone(), access).await {
+        warn!("failed to set access of {}: {}", name, err);
+        return Err(err.to_string());
+    }
+    info!("updated access of {} to {}", name, mode);
+    let _ = app.save_config().await;
+    Ok(())
+}
+
 #[tauri::command]
 async fn request_dir(
     id: String,
     dir: String,
+    password: Option<String>,
     state: tauri::State<'_, Arc<tokio::sync::Mutex<App>>>,
     window: Window,
 ) -> Result<(), ()> {
     let a = Arc::clone(&state);
     let w = Arc::new(window);
     tauri::async_runtime::spawn(async move {
-        let mut addr = String::from("http://");
-        let ip = match a
+        let source = a
             .lock()
             .await
             .sources
@@ -126,18 +324,38 @@ async fn request_dir(
             .await
             .iter()
             .find(|s| s.id == id)
-            .map(|s| s.ip.clone())
-        {
-            Some(Some(ip)) => ip,
-            _ => "127.0.0.1".to_string(),
-        };
+            .cloned();
+        let addr = peer_url(source.as_ref());
 
-        addr.push_str(&ip);
-        addr.push_str(":9001");
+        let (dest, options, known_peers, identity, group) = {
+            let app = a.lock().await;
+            let c = app.config.lock().await;
+            let options = DownloadOptions {
+                limiter: Some(Arc::clone(&app.limiter)),
+                ..c.download_options()
+            };
+            (
+                c.destination.clone(),
+                options,
+                Arc::clone(&app.known_peers),
+                app.identity.clone(),
+                app.group.clone(),
+            )
+        };
 
-        let dest = a.lock().await.config.lock().await.destination.clone();
+        if let Some(s) = source {
+            announce_source(&known_peers, &addr, &s);
+        }
 
-        let c = Arc::new(Client::new(dest.to_str().unwrap().to_string()));
+        let mut c = Client::with_known_peers(dest.to_str().unwrap().to_string(), known_peers)
+            .with_identity(identity);
+        if let Some(p) = password {
+            c = c.with_password(dir.clone(), p);
+        }
+        if let Some(g) = group {
+            c = c.with_group(g);
+        }
+        let c = Arc::new(c);
 
         info!("REQUESTING: {} from {:?}", dir, addr);
 
@@ -151,27 +369,21 @@ async fn request_dir(
                         dir,
                         successful: vec![],
                         failed: vec!["total failure".to_string()],
+                        corrupted: vec![],
                     });
                     
                     return;
                     }
             };
 
-            #[derive(Serialize, Clone)]
-        struct FilePayload {
-            id: String,
-            dir: String,
-            successful: Vec<String>,
-            failed: Vec<String>
-        }
-
-        match c.get_all_files(addr.to_string(), files).await {
-            Ok((s, f)) => {
+        match c.download(addr.to_string(), files, options).await {
+            Ok(r) => {
                 let _ = w.emit_all("files", FilePayload{
                     id,
                     dir,
-                    successful: s,
-                    failed: f
+                    successful: r.successful,
+                    failed: r.failed,
+                    corrupted: r.corrupted,
                 });
             }
             Err(_) => {}
@@ -180,6 +392,90 @@ async fn request_dir(
     Ok(())
 }
 
+#[tauri::command]
+async fn swarm_dir(
+    dir: String,
+    password: Option<String>,
+    state: tauri::State<'_, Arc<tokio::sync::Mutex<App>>>,
+    window: Window,
+) -> Result<(), ()> {
+    let a = Arc::clone(&state);
+    let w = Arc::new(window);
+    tauri::async_runtime::spawn(async move {
+        let peers: Vec<(Source, String)> = a
+            .lock()
+            .await
+            .sources
+            .lock()
+            .await
+            .iter()
+            .filter(|s| s.shared_directories.contains(&dir))
+            .filter_map(|s| s.url().map(|url| (s.clone(), url)))
+            .collect();
+
+        let id = peers
+            .iter()
+            .map(|(s, _)| s.id.clone())
+            .collect::<Vec<String>>()
+            .join(",");
+        let addrs: Vec<String> = peers
+            .iter()
+            .map(|(_, url)| url.clone())
+            .collect();
+
+        let (dest, options, known_peers, identity, group) = {
+            let app = a.lock().await;
+            let c = app.config.lock().await;
+            let options = DownloadOptions {
+                limiter: Some(Arc::clone(&app.limiter)),
+                ..c.download_options()
+            };
+            (
+                c.destination.clone(),
+                options,
+                Arc::clone(&app.known_peers),
+                app.identity.clone(),
+                app.group.clone(),
+            )
+        };
+
+        for (s, addr) in &peers {
+            announce_source(&known_peers, addr, s);
+        }
+
+        let mut c = Client::with_known_peers(dest.to_str().unwrap().to_string(), known_peers)
+            .with_identity(identity);
+        if let Some(p) = password {
+            c = c.with_password(dir.clone(), p);
+        }
+        if let Some(g) = group {
+            c = c.with_group(g);
+        }
+        let c = Arc::new(c);
+
+        info!("SWARMING: {} from {:?}", dir, addrs);
+
+        let payload = match c.swarm(dir.clone(), addrs, options).await {
+            Ok(r) => FilePayload {
+                id,
+                dir,
+                successful: r.successful,
+                failed: r.failed,
+                corrupted: r.corrupted,
+            },
+            Err(_) => FilePayload {
+                id,
+                dir,
+                successful: vec![],
+                failed: vec!["total failure".to_string()],
+                corrupted: vec![],
+            },
+        };
+        let _ = w.emit_all("files", payload);
+    });
+    Ok(())
+}
+
 #[tauri::command]
 async fn app_state(state: tauri::State<'_, Arc<tokio::sync::Mutex<App>>>) -> Result<Config, ()> {
     let app = state.lock().await;
@@ -199,16 +495,15 @@ async fn listen_for(
     
     tauri::async_runtime::spawn(async move {
         loop {
-                    thread::sleep(Duration::from_secs(15));
+                    let interval = b.lock().await.config.lock().await.announce_interval();
+                    tokio::time::sleep(interval).await;
 {       
     
     let c = b.lock().await;
-    let mut dirs = c.sources.lock().await;
-    let len = dirs.len();
-    dirs.retain(|d| !d.is_outdated());
-    if len == dirs.len() {
+    if !c.remove_outdated().await {
         continue;
     }
+    let dirs = c.sources.lock().await;
     let mut payload: Vec<Payload> = vec![];
                 dirs.iter().for_each(|so| {
                     so.shared_directories.iter().for_each(|d| {
@@ -218,6 +513,7 @@ async fn listen_for(
                             so.nickname.clone(),
                             so.ip.clone(),
                             so.timestamp.clone(),
+                            so.interface.clone(),
                         ));
                     })
                 });
@@ -245,6 +541,7 @@ async fn listen_for(
                             so.nickname.clone(),
                             so.ip.clone(),
                             so.timestamp.clone(),
+                            so.interface.clone(),
                         ));
                     })
                 });
@@ -262,6 +559,41 @@ async fn listen_for(
     Ok(())
 }
 
+/// Tells `known_peers` who serves at `addr` and which interface it was seen
+/// on.
+fn announce_source(known_peers: &KnownPeers, addr: &str, source: &Source) {
+    known_peers.announce(addr, &source.id, source.fingerprint.clone());
+    if let Some(local) = source.local_address() {
+        known_peers.route(addr, local);
+    }
+}
+
+/// Where to reach `source`, the local server if its address is unknown.
+fn peer_url(source: Option<&Source>) -> String {
+    source
+        .and_then(|s| s.url())
+        .unwrap_or_else(|| format!("https://127.0.0.1:{}", DEFAULT_PORT))
+}
+
+/// A client for the CLI commands. It acts as this node and trusts the keys
+/// this node pinned.
+fn cli_client(
+    dest: String,
+    dir: &str,
+    password: Option<String>,
+    group: Option<String>,
+) -> Result<Client, LandohError> {
+    let mut c =
+        Client::with_known_peers(dest, Arc::new(known_peers()?)).with_identity(identity()?);
+    if let Some(p) = password {
+        c = c.with_password(dir.to_string(), p);
+    }
+    if let Some(g) = group {
+        c = c.with_group(Arc::new(GroupKey::new(&g)));
+    }
+    Ok(c)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     use clap::{Parser, Subcommand};
@@ -280,6 +612,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             #[arg(short, long, num_args(0..))]
             dirs: Option<Vec<String>>,
         },
+        AddShare {
+            #[arg(short, long)]
+            name: String,
+            #[arg(short, long, num_args(1..))]
+            paths: Vec<String>,
+        },
         TestBroadcast {
             #[arg(short, long)]
             nickname: Option<String>,
@@ -287,6 +625,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             id: Option<String>,
             #[arg(short, long, num_args(0..))]
             dirs: Option<Vec<String>>,
+            #[arg(long)]
+            group: Option<String>,
         },
         GetAllFiles {
             #[arg(short, long)]
@@ -295,22 +635,98 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             port: Option<String>,
             #[arg(long)]
             dir: String,
+            #[arg(long)]
+            password: Option<String>,
+            #[arg(long)]
+            group: Option<String>,
             #[arg(short, long)]
             destination: Option<String>,
+            #[arg(short, long)]
+            concurrency: Option<usize>,
+            #[arg(long)]
+            chunk_size: Option<usize>,
+            #[arg(long)]
+            adaptive: bool,
+            #[arg(long)]
+            compression: Option<Compression>,
+            #[arg(long)]
+            limit: Option<u64>,
+        },
+        SendFile {
+            #[arg(short, long)]
+            source: String,
+            #[arg(short, long)]
+            port: Option<String>,
+            #[arg(short, long)]
+            file: String,
+            #[arg(long)]
+            group: Option<String>,
         },
         ListDirectories {
             #[arg(short, long)]
             source: String,
             #[arg(short, long)]
             port: Option<String>,
+            #[arg(long)]
+            group: Option<String>,
+        },
+        Swarm {
+            #[arg(short, long, num_args(1..))]
+            sources: Vec<String>,
+            #[arg(short, long)]
+            port: Option<String>,
+            #[arg(long)]
+            dir: String,
+            #[arg(long)]
+            password: Option<String>,
+            #[arg(long)]
+            group: Option<String>,
+            #[arg(short, long)]
+            destination: Option<String>,
+            #[arg(short, long)]
+            concurrency: Option<usize>,
+            #[arg(long)]
+            chunk_size: Option<usize>,
+            #[arg(long)]
+            adaptive: bool,
+            #[arg(long)]
+            compression: Option<Compression>,
+            #[arg(long)]
+            limit: Option<u64>,
+        },
+        Sync {
+            #[arg(short, long)]
+            source: String,
+            #[arg(short, long)]
+            port: Option<String>,
+            #[arg(long)]
+            dir: String,
+            #[arg(long)]
+            password: Option<String>,
+            #[arg(long)]
+            group: Option<String>,
+            #[arg(short, long)]
+            destination: Option<String>,
+            #[arg(long)]
+            delete: bool,
+            #[arg(long)]
+            dry_run: bool,
         },
     }
 
     let cli = Cli::parse();
 
     match cli.command {
-        Some(Commands::TestBroadcast { dirs, nickname, id }) => {
-            let tx = landoh::multicast::Sender::new().unwrap();
+        Some(Commands::TestBroadcast {
+            dirs,
+            nickname,
+            id,
+            group,
+        }) => {
+            let tx = landoh::multicast::Sender::new(
+                group.map(|g| Arc::new(GroupKey::new(&g))),
+                &InterfaceFilter::default(),
+            )?;
             let mut def = vec!["root".to_string(), "testdir".to_string()];
             let dirs = match dirs {
                 Some(mut d) => {
@@ -331,27 +747,51 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             };
 
             let s = Source::new(uid, nick, None, dirs);
+            // Signed like this node's own announcements, so peers that
+            // pinned its key accept the test too.
+            let key = signing_key()?;
+
+            println!("sending payload: {:?} signed by {}", &s, key.public_key());
+
+            let _ = tx.send(key.sign(&s)?).await;
+        }
+        Some(Commands::AddShare { name, paths }) => {
+            let mut app = match App::new_from_config() {
+                Ok(a) => a,
+                Err(_) => {
+                    let addr = SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT));
+                    App::new(Config::new(vec![], "downloads".to_string(), addr, None)?)?
+                }
+            };
 
-            println!("sending payload: {:?}", &s);
+            app.add_shared_dir(name, paths).await?;
+            app.save_config().await?;
 
-            let _ = tx.send(s).await;
+            for d in app.config.lock().await.shared_directories.iter() {
+                println!("{}: {:?}", d.name, d.paths);
+            }
         }
         Some(Commands::Serve { dirs, address }) => {
-            let addr: SocketAddr = match address {
-                Some(addr) => addr.as_str().parse()?,
-                None => "0.0.0.0:9001".parse()?,
+            let address: Option<SocketAddr> = match address {
+                Some(addr) => Some(addr.as_str().parse()?),
+                None => None,
             };
+            let addr = address.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT)));
             let dirs = match dirs {
                 Some(dirs) => dirs,
                 None => vec![],
             };
 
-            let config = Config::new(dirs, "testdestination".to_string(), addr, None).unwrap();
+            let config = Config::new(dirs, "testdestination".to_string(), addr, None)?;
 
             let mut app = match App::new_from_config() {
                 Ok(a) => a,
-                Err(_) => App::new(config),
+                Err(_) => App::new(config)?,
             };
+            // Served and announced instead of the configured address.
+            if let Some(addr) = address {
+                app.config.lock().await.address = addr;
+            }
 
             app.listen().await;
             let s = Arc::clone(&app.sources);
@@ -363,50 +803,192 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             });
             app.broadcast().await;
             app.serve().await;
-            app.join_all().await;
+            tokio::select! {
+                _ = app.join_all() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            if let Err(err) = app.goodbye().await {
+                warn!("failed to say goodbye: {}", err);
+            }
         }
         Some(Commands::GetAllFiles {
             source,
             port,
             dir,
+            password,
+            group,
             destination,
+            concurrency,
+            chunk_size,
+            adaptive,
+            compression,
+            limit,
         }) => {
-            let mut addr = String::from("http://");
+            let mut addr = String::from("https://");
             addr.push_str(&source);
             match port {
                 Some(p) => addr.push_str(&p),
-                None => addr.push_str(":9001"),
+                None => addr.push_str(&format!(":{}", DEFAULT_PORT)),
             };
             let dest = match destination {
                 Some(d) => d,
                 None => ".".to_string(),
             };
 
-            let c = Arc::new(Client::new(dest));
+            let c = Arc::new(cli_client(dest, &dir, password, group)?);
 
-            let files = c.get_directory(dir, addr.to_string()).await.unwrap();
-            c.get_all_files(addr.to_string(), files).await.unwrap();
+            let mut options = DownloadOptions::default();
+            if let Some(n) = concurrency {
+                options.concurrency = n;
+            }
+            options.chunk_size = match (adaptive, chunk_size) {
+                (true, _) => ChunkSize::Adaptive,
+                (false, Some(s)) => ChunkSize::Fixed(s),
+                (false, None) => ChunkSize::Peer,
+            };
+            if let Some(c) = compression {
+                options.compression = c;
+            }
+            if let Some(l) = limit {
+                options.limiter = Some(Arc::new(Limiter::new(&RateLimits {
+                    download: l,
+                    ..RateLimits::default()
+                })));
+            }
+
+            let files = c.get_directory(dir, addr.to_string()).await?;
+            c.download(addr.to_string(), files, options).await?;
         }
-        Some(Commands::ListDirectories { source, port }) => {
-            let mut addr = String::from("http://");
+        Some(Commands::Sync {
+            source,
+            port,
+            dir,
+            password,
+            group,
+            destination,
+            delete,
+            dry_run,
+        }) => {
+            let mut addr = String::from("https://");
             addr.push_str(&source);
             match port {
                 Some(p) => addr.push_str(&p),
-                None => addr.push_str(":9001"),
+                None => addr.push_str(&format!(":{}", DEFAULT_PORT)),
+            };
+            let dest = match destination {
+                Some(d) => d,
+                None => ".".to_string(),
             };
 
-            let c = Arc::new(Client::new(String::from(".")));
+            let c = Arc::new(cli_client(dest, &dir, password, group)?);
+
+            let files = c.get_directory(dir.clone(), addr.to_string()).await?;
+            let plan = c.plan_sync(&dir, files, delete).await?;
+
+            println!("{}", plan);
+
+            if !dry_run && !plan.is_empty() {
+                let (_, failed) = c.sync(addr.to_string(), plan).await?;
+                if !failed.is_empty() {
+                    println!("failed: {:?}", failed);
+                }
+            }
+        }
+        Some(Commands::Swarm {
+            sources,
+            port,
+            dir,
+            password,
+            group,
+            destination,
+            concurrency,
+            chunk_size,
+            adaptive,
+            compression,
+            limit,
+        }) => {
+            let addrs = sources
+                .iter()
+                .map(|s| {
+                    let mut addr = String::from("https://");
+                    addr.push_str(s);
+                    match port {
+                        Some(ref p) => addr.push_str(p),
+                        None => addr.push_str(&format!(":{}", DEFAULT_PORT)),
+                    };
+                    addr
+                })
+                .collect();
+            let dest = match destination {
+                Some(d) => d,
+                None => ".".to_string(),
+            };
+
+            let c = Arc::new(cli_client(dest, &dir, password, group)?);
+
+            let mut options = DownloadOptions::default();
+            if let Some(n) = concurrency {
+                options.concurrency = n;
+            }
+            options.chunk_size = match (adaptive, chunk_size) {
+                (true, _) => ChunkSize::Adaptive,
+                (false, Some(s)) => ChunkSize::Fixed(s),
+                (false, None) => ChunkSize::Peer,
+            };
+            if let Some(c) = compression {
+                options.compression = c;
+            }
+            if let Some(l) = limit {
+                options.limiter = Some(Arc::new(Limiter::new(&RateLimits {
+                    download: l,
+                    ..RateLimits::default()
+                })));
+            }
+
+            c.swarm(dir, addrs, options).await?;
+        }
+        Some(Commands::ListDirectories {
+            source,
+            port,
+            group,
+        }) => {
+            let mut addr = String::from("https://");
+            addr.push_str(&source);
+            match port {
+                Some(p) => addr.push_str(&p),
+                None => addr.push_str(&format!(":{}", DEFAULT_PORT)),
+            };
+
+            let c = Arc::new(cli_client(String::from("."), "", None, group)?);
 
             c.list_directories(addr).await?;
         }
+        Some(Commands::SendFile {
+            source,
+            port,
+            file,
+            group,
+        }) => {
+            let mut addr = String::from("https://");
+            addr.push_str(&source);
+            match port {
+                Some(p) => addr.push_str(&p),
+                None => addr.push_str(&format!(":{}", DEFAULT_PORT)),
+            };
+
+            let c = cli_client(String::from("."), "", None, group)?;
+
+            let stored = c.send_file(addr, PathBuf::from(file)).await?;
+            println!("stored as {}", stored);
+        }
         _ => {
             let app = match App::new_from_config() {
                 Ok(a) => a,
                 Err(_) => {
-                    let addr: SocketAddr = "0.0.0.0:9001".parse().unwrap();
+                    let addr = SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT));
 
-                    let config = Config::new(vec![], "downloads".to_string(), addr, None).unwrap();
-                    App::new(config)
+                    let config = Config::new(vec![], "downloads".to_string(), addr, None)?;
+                    App::new(config)?
                 }
             };
 
@@ -416,16 +998,43 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     serve,
                     listen_for,
                     request_dir,
+                    swarm_dir,
                     app_state,
                     update_nickname,
                     update_destination,
+                    update_limits,
                     broadcast,
                     add_shared_dir,
                     remove_shared_dir,
+                    remove_shared_path,
+                    forget_peer,
+                    set_share_access,
+                    answer_approval,
+                    update_ask_before_serving,
+                    answer_incoming_file,
+                    send_file,
                     open_dir,
                 ])
-                .run(tauri::generate_context!())
-                .expect("error while running tauri application");
+                .build(tauri::generate_context!())
+                .expect("error while building tauri application")
+                .run(|handle, event| {
+                    // This runs inside the runtime of main, which cannot be
+                    // blocked on. The exit waits for the goodbye instead.
+                    if let tauri::RunEvent::ExitRequested { api, .. } = event {
+                        api.prevent_exit();
+                        let handle = handle.clone();
+                        tauri::async_runtime::spawn(async move {
+                            let app = handle.state::<Arc<Mutex<App>>>();
+                            let goodbye = async { app.lock().await.goodbye().await };
+                            match tokio::time::timeout(Duration::from_secs(2), goodbye).await {
+                                Ok(Ok(_)) => {}
+                                Ok(Err(err)) => warn!("failed to say goodbye: {}", err),
+                                Err(_) => warn!("gave up saying goodbye"),
+                            }
+                            handle.exit(0);
+                        });
+                    }
+                });
         }
     };
 
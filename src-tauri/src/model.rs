@@ -4,4 +4,8 @@ pub enum OSPATHS {
     PROGRAMDATA,
 }
 
-pub const CHUNK_SIZE: usize = 4069;
+/// Size of the chunks files are streamed in unless a peer asks for another one.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+pub const MIN_CHUNK_SIZE: usize = 4 * 1024;
+/// Stays well below the 4 MiB message limit of tonic.
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
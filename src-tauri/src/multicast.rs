@@ -1,29 +1,85 @@
 pub const PORT: u16 = 7645;
+pub const IPV4_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 123);
+/// Link-local, so announcements stay on the network like those over IPv4.
+pub const IPV6_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x123);
 
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use std::{
-    error::Error,
-    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
-    sync::Arc,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
+use log::warn;
 use tokio::sync::Mutex;
 
-use serde::Serialize;
+use crate::error::LandohError;
+use crate::group::GroupKey;
+use crate::interface::{self, Interface, InterfaceFilter};
+use crate::signing::Announcement;
+use crate::wire::{self, Kind};
 
 #[derive(Debug)]
 pub struct Sender {
-    socket: Arc<Mutex<UdpSocket>>,
-    addr: SocketAddr,
+    /// A socket for every interface and IP version multicast is available
+    /// on, with the group it sends to.
+    sockets: Arc<Mutex<Vec<(UdpSocket, SocketAddr)>>>,
+    /// Announcements are encrypted for the members of this group.
+    group: Option<Arc<GroupKey>>,
+    /// Tells the fragments of different announcements apart.
+    messages: AtomicU32,
 }
 impl Sender {
-    pub fn new() -> Result<Self, Box<dyn Error>> {
-        let ipv4: IpAddr = Ipv4Addr::new(224, 0, 0, 123).into();
-        let addr = SocketAddr::new(ipv4, PORT);
+    /// Announces over IPv4 and IPv6 on every interface `filter` allows,
+    /// failing only if none of them is available.
+    pub fn new(
+        group: Option<Arc<GroupKey>>,
+        filter: &InterfaceFilter,
+    ) -> Result<Self, LandohError> {
+        let mut bound = vec![];
+        for i in interfaces(filter)? {
+            if let Some(ip) = i.ipv4() {
+                bound.push((i.name.clone(), Self::bind_v4(ip)));
+            }
+            if i.has_ipv6() {
+                bound.push((i.name.clone(), Self::bind_v6(i.index)));
+            }
+        }
+
+        let mut sockets = vec![];
+        let mut failure = None;
+        for (name, b) in bound {
+            match b {
+                Ok(s) => sockets.push(s),
+                Err(err) => {
+                    warn!("failed to set up multicast sender on {}: {}", name, err);
+                    failure = Some(err);
+                }
+            }
+        }
+        if let (true, Some(err)) = (sockets.is_empty(), failure) {
+            return Err(LandohError::Discovery(format!(
+                "failed to set up multicast sender: {}",
+                err
+            )));
+        }
+
+        Ok(Sender {
+            sockets: Arc::new(Mutex::new(sockets)),
+            group,
+            messages: AtomicU32::new(0),
+        })
+    }
+
+    fn bind_v4(interface: Ipv4Addr) -> io::Result<(UdpSocket, SocketAddr)> {
+        let addr = SocketAddr::new(IPV4_GROUP.into(), PORT);
         let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
 
-        socket.set_multicast_if_v4(&Ipv4Addr::new(0, 0, 0, 0))?;
+        socket.set_multicast_if_v4(&interface)?;
 
         socket.bind(&SockAddr::from(SocketAddr::new(
             Ipv4Addr::new(0, 0, 0, 0).into(),
@@ -32,80 +88,173 @@ impl Sender {
 
         socket.set_read_timeout(Some(Duration::from_millis(100)))?;
 
-        let socket: UdpSocket = socket.into();
-        Ok(Sender {
-            socket: Arc::new(Mutex::new(socket)),
-            addr,
-        })
+        Ok((socket.into(), addr))
     }
 
-    pub async fn send<T: Serialize>(&self, data: T) -> Result<(), Box<dyn Error>> {
-        let payload = serde_json::to_string(&data)?;
+    fn bind_v6(interface: u32) -> io::Result<(UdpSocket, SocketAddr)> {
+        let addr = SocketAddr::new(IPV6_GROUP.into(), PORT);
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
 
-        self.socket
-            .lock()
-            .await
-            .send_to(payload.as_bytes(), &self.addr)?;
-        Ok(())
+        socket.set_only_v6(true)?;
+        socket.set_multicast_if_v6(interface)?;
+
+        socket.bind(&SockAddr::from(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)))?;
+
+        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+
+        Ok((socket.into(), addr))
+    }
+
+    pub async fn send(&self, announcement: Announcement) -> Result<(), LandohError> {
+        let mut datagram = announcement.encode()?;
+        if let Some(g) = &self.group {
+            datagram = wire::envelope(Kind::Sealed, &g.seal(&datagram)?);
+        }
+        let message = self.messages.fetch_add(1, Ordering::Relaxed);
+        let fragments = wire::fragment(datagram, message)?;
+
+        // Sent on every socket, announcing succeeds if any of them works.
+        let mut failure = None;
+        let mut sent = false;
+        for (socket, addr) in self.sockets.lock().await.iter() {
+            match fragments.iter().try_for_each(|f| socket.send_to(f, addr).map(|_| ())) {
+                Ok(_) => sent = true,
+                Err(err) => failure = Some(err),
+            }
+        }
+        match (sent, failure) {
+            (false, Some(err)) => Err(LandohError::Discovery(format!(
+                "failed to announce: {}",
+                err
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The interfaces discovery runs on. Without any to tell apart, the system
+/// picks the interface of its default route.
+fn interfaces(filter: &InterfaceFilter) -> Result<Vec<Interface>, LandohError> {
+    let found = match interface::list(filter) {
+        Ok(i) => i,
+        Err(err) => {
+            warn!("failed to list network interfaces: {}", err);
+            vec![]
+        }
+    };
+    match (found.is_empty(), filter.is_empty()) {
+        (false, _) => Ok(found),
+        (true, true) => Ok(vec![Interface {
+            name: "default".to_string(),
+            index: 0,
+            addrs: vec![
+                (Ipv4Addr::UNSPECIFIED.into(), Ipv4Addr::UNSPECIFIED.into()),
+                (Ipv6Addr::UNSPECIFIED.into(), Ipv6Addr::UNSPECIFIED.into()),
+            ],
+        }]),
+        (true, false) => Err(LandohError::Discovery(format!(
+            "no network interface matches {:?}",
+            filter
+        ))),
     }
 }
 
 pub mod receiver {
     use socket2::{Domain, Protocol, Socket, Type};
     use std::{
-        error::Error,
+        collections::HashSet,
         io,
-        net::{Ipv4Addr, SocketAddr, UdpSocket},
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
         sync::{mpsc::Sender, Arc},
         time::Duration,
     };
 
+    use chrono::Utc;
     use tokio::sync::Mutex;
 
-    use log::error;
+    use log::{error, warn};
 
     pub use crate::source::Source;
+    use crate::source::PROTOCOL_VERSION;
 
+    use crate::error::LandohError;
+    use crate::group::GroupKey;
+    use crate::interface::{seen_on, Interface, InterfaceFilter};
+    use crate::signing::Verifier;
+    use crate::tls::KnownPeers;
+    use crate::wire::{Reassembler, RECEIVE_BUFFER};
+
+    /// Keeps `sources` up to date with the announcements of other peers on
+    /// the interfaces `filter` allows. Only announcements signed with the key
+    /// pinned in `known_peers` are accepted, and with a `group` only those of
+    /// its members. Peers speaking another protocol version are left out.
+    /// Peers are kept for `peer_ttl`, which also bounds how long they keep
+    /// the address they were first heard on.
     pub async fn listen(
         id: String,
         sources: Arc<Mutex<Vec<Source>>>,
         sender: Option<Sender<Vec<Source>>>,
-    ) -> Result<(), Box<dyn Error>> {
-        let ipv4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 123).into();
-        let addr = SocketAddr::new(ipv4.clone().into(), super::PORT);
-
-        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-
-        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
-        socket.join_multicast_v4(&ipv4, &Ipv4Addr::UNSPECIFIED)?;
-        bind_multicast(&socket, &addr)?;
-        let listener: UdpSocket = socket.into();
-        loop {
-            let mut buf = [0u8; 1024];
+        known_peers: Arc<KnownPeers>,
+        group: Option<Arc<GroupKey>>,
+        filter: InterfaceFilter,
+        peer_ttl: chrono::Duration,
+    ) -> Result<(), LandohError> {
+        let interfaces = super::interfaces(&filter)?;
+        let listeners = join(&interfaces)?;
+        let mut verifier = Verifier::new(id, known_peers, group);
+        let mut fragments = Reassembler::default();
+        // Peers already warned about, they announce again and again.
+        let mut incompatible = HashSet::new();
+        let mut buf = vec![0u8; RECEIVE_BUFFER];
+        // Every socket waits for its read timeout at most, so none of them
+        // holds up the others.
+        for listener in listeners.iter().cycle() {
             match listener.recv_from(&mut buf) {
                 Ok((len, remote_addr)) => {
-                    if remote_addr.to_string() == id {
-                        ()
-                    }
-                    let d = &buf[..len];
-                    let payload_raw = serde_json::from_slice::<Source>(d);
-                    match payload_raw {
-                        Ok(p) => {
-                            if p.id == id {
-                                continue;
-                            }
-
+                    let d = match fragments.push(remote_addr, &buf[..len]) {
+                        Ok(Some(d)) => d,
+                        Ok(None) => continue,
+                        Err(err) => {
+                            warn!("{} from {}", err, remote_addr);
+                            continue;
+                        }
+                    };
+                    match verifier.accept(&d) {
+                        Ok(None) => {}
+                        Ok(Some(p)) => {
                             let mut dirs = sources.lock().await;
+                            // The announced port and protocol are kept, so
+                            // the peer is dialed where it actually serves.
+                            let ip = Some(source_ip(remote_addr));
+                            let p = Source {
+                                interface: seen_on(&interfaces, remote_addr),
+                                ..p
+                            };
                             match dirs.iter_mut().find(|ref i| i.id == p.id) {
+                                _ if p.leaving => {
+                                    dirs.retain(|i| i.id != p.id);
+                                }
+                                _ if !p.is_compatible() => {
+                                    if incompatible.insert(p.id.clone()) {
+                                        warn!(
+                                            "ignoring {} at {}, it speaks protocol {:?} instead of {}",
+                                            p.id, remote_addr, p.protocol, PROTOCOL_VERSION
+                                        );
+                                    }
+                                    dirs.retain(|i| i.id != p.id);
+                                }
                                 Some(ref mut i) => {
-                                    i.update(p.nickname, Some(remote_addr.ip().to_string()), p.shared_directories);
+                                    i.update(p, ip, peer_ttl);
+                                }
+                                None => {
+                                    let now = Utc::now();
+                                    dirs.push(Source {
+                                        ip,
+                                        timestamp: now,
+                                        address_seen: Some(now),
+                                        ..p
+                                    });
                                 }
-                                None => dirs.push(Source::new(
-                                    p.id,
-                                    p.nickname,
-                                    Some(remote_addr.ip().to_string()),
-                                    p.shared_directories,
-                                )),
                             };
 
                             let _ = match sender {
@@ -113,18 +262,109 @@ pub mod receiver {
                                 None => Ok(()),
                             };
                         }
+                        Err(err @ LandohError::KeyChanged { .. }) => {
+                            error!("{} from {}", err, remote_addr);
+                        }
                         Err(err) => {
-                            error!("{}", err);
+                            warn!("{} from {}", err, remote_addr);
                         }
                     }
                 }
-                Err(_) => {}
+                // Timed out, lets the runtime stop the listener on shutdown.
+                Err(_) => tokio::task::yield_now().await,
             }
         }
         #[allow(unreachable_code)]
         Ok(())
     }
 
+    /// Link-local IPv6 addresses are only reachable through the interface
+    /// they were seen on, so its index is kept as the zone: `fe80::1%3`.
+    fn source_ip(addr: SocketAddr) -> String {
+        match addr {
+            SocketAddr::V6(a) if a.scope_id() != 0 => format!("{}%{}", a.ip(), a.scope_id()),
+            a => a.ip().to_string(),
+        }
+    }
+
+    /// Joins the IPv4 and the IPv6 group on `interfaces`, failing only if
+    /// neither works.
+    fn join(interfaces: &[Interface]) -> Result<Vec<UdpSocket>, LandohError> {
+        let mut listeners = vec![];
+        let mut failure = None;
+        for joined in [join_v4(interfaces), join_v6(interfaces)] {
+            match joined {
+                Ok(s) => listeners.push(s),
+                Err(err) => {
+                    warn!("failed to join multicast group: {}", err);
+                    failure = Some(err);
+                }
+            }
+        }
+        match (listeners.is_empty(), failure) {
+            (true, Some(err)) => Err(LandohError::Discovery(format!(
+                "failed to join multicast group: {}",
+                err
+            ))),
+            _ => Ok(listeners),
+        }
+    }
+
+    fn join_v4(interfaces: &[Interface]) -> io::Result<UdpSocket> {
+        let ipv4: Ipv4Addr = super::IPV4_GROUP;
+        let addr = SocketAddr::new(ipv4.clone().into(), super::PORT);
+
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+
+        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+        joined_any(
+            interfaces
+                .iter()
+                .filter_map(|i| i.ipv4().map(|ip| (i, socket.join_multicast_v4(&ipv4, &ip)))),
+        )?;
+        bind_multicast(&socket, &addr)?;
+        Ok(socket.into())
+    }
+
+    fn join_v6(interfaces: &[Interface]) -> io::Result<UdpSocket> {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+
+        socket.set_only_v6(true)?;
+        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+        joined_any(
+            interfaces
+                .iter()
+                .filter(|i| i.has_ipv6())
+                .map(|i| (i, socket.join_multicast_v6(&super::IPV6_GROUP, i.index))),
+        )?;
+        // Binding to a link-local group needs a zone on some systems, the
+        // unspecified address works everywhere.
+        let addr = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), super::PORT);
+        socket.bind(&socket2::SockAddr::from(addr))?;
+        Ok(socket.into())
+    }
+
+    /// Succeeds if the group was joined on any interface.
+    fn joined_any<'a>(
+        joins: impl Iterator<Item = (&'a Interface, io::Result<()>)>,
+    ) -> io::Result<()> {
+        let mut failure = io::Error::new(io::ErrorKind::NotFound, "no interface to join on");
+        let mut joined = false;
+        for (i, result) in joins {
+            match result {
+                Ok(()) => joined = true,
+                Err(err) => {
+                    warn!("failed to join multicast group on {}: {}", i.name, err);
+                    failure = err;
+                }
+            }
+        }
+        match joined {
+            true => Ok(()),
+            false => Err(failure),
+        }
+    }
+
     #[cfg(windows)]
 
     fn bind_multicast(socket: &Socket, addr: &SocketAddr) -> io::Result<()> {
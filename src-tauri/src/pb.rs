@@ -12,6 +12,9 @@ pub struct ListDirectoriesResponse {
 pub struct GetDirectoryRequest {
     #[prost(string, tag = "1")]
     pub name: ::prost::alloc::string::String,
+    /// For shares protected by a password.
+    #[prost(string, optional, tag = "2")]
+    pub password: ::core::option::Option<::prost::alloc::string::String>,
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -24,11 +27,22 @@ pub struct GetDirectoryResponse {
 pub struct GetFileRequest {
     #[prost(string, tag = "1")]
     pub path: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "2")]
+    pub offset: u64,
+    #[prost(uint64, optional, tag = "3")]
+    pub length: ::core::option::Option<u64>,
+    #[prost(uint32, optional, tag = "4")]
+    pub chunk_size: ::core::option::Option<u32>,
+    #[prost(enumeration = "Compression", tag = "5")]
+    pub compression: i32,
+    /// For shares protected by a password.
+    #[prost(string, optional, tag = "6")]
+    pub password: ::core::option::Option<::prost::alloc::string::String>,
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct GetFileResponse {
-    #[prost(oneof = "get_file_response::FileResponse", tags = "1, 2")]
+    #[prost(oneof = "get_file_response::FileResponse", tags = "1, 2, 3, 4")]
     pub file_response: ::core::option::Option<get_file_response::FileResponse>,
 }
 /// Nested message and enum types in `GetFileResponse`.
@@ -37,13 +51,53 @@ pub mod get_file_response {
     #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum FileResponse {
         #[prost(bytes, tag = "1")]
-        Chunk(::prost::alloc::vec::Vec<u8>),
+        Chunk(::prost::bytes::Bytes),
         #[prost(message, tag = "2")]
         Meta(super::FileMetaData),
+        #[prost(message, tag = "3")]
+        CompressedChunk(super::CompressedChunk),
+        /// Sent before any data if the request has to wait for a free slot.
+        #[prost(uint32, tag = "4")]
+        QueuePosition(u32),
     }
 }
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SendFileRequest {
+    #[prost(oneof = "send_file_request::SendRequest", tags = "1, 2")]
+    pub send_request: ::core::option::Option<send_file_request::SendRequest>,
+}
+/// Nested message and enum types in `SendFileRequest`.
+pub mod send_file_request {
+    #[allow(clippy::derive_partial_eq_without_eq)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum SendRequest {
+        /// Sent first. The path is relative to the receiver's destination.
+        #[prost(message, tag = "1")]
+        Meta(super::FileMetaData),
+        #[prost(bytes, tag = "2")]
+        Chunk(::prost::bytes::Bytes),
+    }
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SendFileResponse {
+    /// Where the file was stored, relative to the receiver's destination.
+    #[prost(string, tag = "1")]
+    pub path: ::prost::alloc::string::String,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CompressedChunk {
+    #[prost(enumeration = "Compression", tag = "1")]
+    pub compression: i32,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub data: ::prost::bytes::Bytes,
+    #[prost(uint32, tag = "3")]
+    pub size: u32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct FileMetaData {
     #[prost(uint64, tag = "1")]
     pub file_size: u64,
@@ -61,6 +115,71 @@ pub struct Directory {
     #[prost(string, repeated, tag = "2")]
     pub paths: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// Announcements are sent to the multicast group rather than over gRPC.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Announcement {
+    /// An encoded AnnouncedSource, exactly as signed.
+    #[prost(bytes = "bytes", tag = "1")]
+    pub source: ::prost::bytes::Bytes,
+    /// Ed25519 public key of the peer.
+    #[prost(bytes = "bytes", tag = "2")]
+    pub key: ::prost::bytes::Bytes,
+    #[prost(bytes = "bytes", tag = "3")]
+    pub signature: ::prost::bytes::Bytes,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AnnouncedSource {
+    #[prost(string, tag = "1")]
+    pub id: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub nickname: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag = "3")]
+    pub shared_directories: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// Since the Unix epoch.
+    #[prost(int64, tag = "4")]
+    pub seconds: i64,
+    #[prost(uint32, tag = "5")]
+    pub nanos: u32,
+    #[prost(string, optional, tag = "6")]
+    pub fingerprint: ::core::option::Option<::prost::alloc::string::String>,
+    #[prost(uint32, optional, tag = "7")]
+    pub port: ::core::option::Option<u32>,
+    #[prost(uint32, optional, tag = "8")]
+    pub protocol: ::core::option::Option<u32>,
+    #[prost(bool, tag = "9")]
+    pub leaving: bool,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Compression {
+    None = 0,
+    Lz4 = 1,
+    Zstd = 2,
+}
+impl Compression {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Compression::None => "NONE",
+            Compression::Lz4 => "LZ4",
+            Compression::Zstd => "ZSTD",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "NONE" => Some(Self::None),
+            "LZ4" => Some(Self::Lz4),
+            "ZSTD" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+}
 /// Generated client implementations.
 pub mod lan_doh_client {
     #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
@@ -215,6 +334,30 @@ pub mod lan_doh_client {
                 .insert(GrpcMethod::new("pb.lan_doh", "ListDirectories"));
             self.inner.unary(req, path, codec).await
         }
+        /// Offers a file to the peer, which stores it in its destination if its
+        /// owner accepts it.
+        pub async fn send_file(
+            &mut self,
+            request: impl tonic::IntoStreamingRequest<Message = super::SendFileRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::SendFileResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/pb.lan_doh/SendFile");
+            let mut req = request.into_streaming_request();
+            req.extensions_mut().insert(GrpcMethod::new("pb.lan_doh", "SendFile"));
+            self.inner.client_streaming(req, path, codec).await
+        }
     }
 }
 /// Generated server implementations.
@@ -248,6 +391,15 @@ pub mod lan_doh_server {
             tonic::Response<super::ListDirectoriesResponse>,
             tonic::Status,
         >;
+        /// Offers a file to the peer, which stores it in its destination if its
+        /// owner accepts it.
+        async fn send_file(
+            &self,
+            request: tonic::Request<tonic::Streaming<super::SendFileRequest>>,
+        ) -> std::result::Result<
+            tonic::Response<super::SendFileResponse>,
+            tonic::Status,
+        >;
     }
     #[derive(Debug)]
     pub struct LanDohServer<T: LanDoh> {
@@ -467,6 +619,54 @@ pub mod lan_doh_server {
                     };
                     Box::pin(fut)
                 }
+                "/pb.lan_doh/SendFile" => {
+                    #[allow(non_camel_case_types)]
+                    struct SendFileSvc<T: LanDoh>(pub Arc<T>);
+                    impl<
+                        T: LanDoh,
+                    > tonic::server::ClientStreamingService<super::SendFileRequest>
+                    for SendFileSvc<T> {
+                        type Response = super::SendFileResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<
+                                tonic::Streaming<super::SendFileRequest>,
+                            >,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as LanDoh>::send_file(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SendFileSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.client_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => {
                     Box::pin(async move {
                         Ok(
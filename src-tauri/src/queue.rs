@@ -0,0 +1,288 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::error::LandohError;
+
+/// Caps on the GetFile streams a server runs at the same time. 0 means
+/// unlimited.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct TransferLimits {
+    pub global: usize,
+    pub per_peer: usize,
+    /// Requests waiting for a free slot. Beyond this, requests are refused.
+    pub queue: usize,
+}
+
+impl Default for TransferLimits {
+    fn default() -> Self {
+        TransferLimits {
+            global: 32,
+            per_peer: 4,
+            queue: 256,
+        }
+    }
+}
+
+/// A running transfer. Dropping it frees the slot for the next request.
+#[derive(Debug)]
+pub(crate) struct Permit {
+    queue: Option<Arc<TransferQueue>>,
+    peer: IpAddr,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(q) = self.queue.take() {
+            q.release(self.peer);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Admission {
+    Ready(Permit),
+    /// `position` counts all requests waiting, including this one.
+    Queued {
+        position: usize,
+        permit: oneshot::Receiver<Permit>,
+    },
+}
+
+#[derive(Debug, Default)]
+struct Peer {
+    active: usize,
+    waiting: VecDeque<oneshot::Sender<Permit>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    limits: TransferLimits,
+    active: usize,
+    queued: usize,
+    peers: HashMap<IpAddr, Peer>,
+    /// Peers with waiting requests, the one to be served next in front.
+    rotation: VecDeque<IpAddr>,
+}
+
+impl State {
+    fn global_full(&self) -> bool {
+        self.limits.global != 0 && self.active >= self.limits.global
+    }
+
+    fn peer_full(&self, peer: &IpAddr) -> bool {
+        let active = self.peers.get(peer).map(|p| p.active).unwrap_or(0);
+        self.limits.per_peer != 0 && active >= self.limits.per_peer
+    }
+
+    /// Forgets requests that were cancelled while they waited.
+    fn prune(&mut self) {
+        for p in self.peers.values_mut() {
+            p.waiting.retain(|tx| !tx.is_closed());
+        }
+        self.queued = self.peers.values().map(|p| p.waiting.len()).sum();
+    }
+}
+
+/// Admits GetFile streams within `TransferLimits`. Requests over the limits
+/// wait in one queue per peer, and freed slots go to the peers round-robin, so
+/// a peer asking for many files cannot starve the others.
+#[derive(Debug, Default)]
+pub(crate) struct TransferQueue {
+    state: Mutex<State>,
+}
+
+impl TransferQueue {
+    pub(crate) fn acquire(
+        self: &Arc<Self>,
+        peer: IpAddr,
+        limits: TransferLimits,
+    ) -> Result<Admission, LandohError> {
+        let mut s = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        // Raised limits may let waiting requests start.
+        s.limits = limits;
+        self.dispatch(&mut s);
+
+        let waiting = s
+            .peers
+            .get(&peer)
+            .map(|p| !p.waiting.is_empty())
+            .unwrap_or(false);
+
+        if !waiting && !s.global_full() && !s.peer_full(&peer) {
+            s.active += 1;
+            s.peers.entry(peer).or_default().active += 1;
+            return Ok(Admission::Ready(Permit {
+                queue: Some(Arc::clone(self)),
+                peer,
+            }));
+        }
+
+        if limits.queue != 0 && s.queued >= limits.queue {
+            s.prune();
+        }
+        if limits.queue != 0 && s.queued >= limits.queue {
+            return Err(LandohError::Busy(format!(
+                "{} transfers are already waiting",
+                s.queued
+            )));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        s.peers.entry(peer).or_default().waiting.push_back(tx);
+        if !s.rotation.contains(&peer) {
+            s.rotation.push_back(peer);
+        }
+        s.queued += 1;
+
+        Ok(Admission::Queued {
+            position: s.queued,
+            permit: rx,
+        })
+    }
+
+    fn release(self: &Arc<Self>, peer: IpAddr) {
+        let mut s = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        s.active = s.active.saturating_sub(1);
+        if let Some(p) = s.peers.get_mut(&peer) {
+            p.active = p.active.saturating_sub(1);
+        }
+        self.dispatch(&mut s);
+        s.peers.retain(|_, p| p.active > 0 || !p.waiting.is_empty());
+    }
+
+    /// Hands free slots to waiting requests, one peer at a time.
+    fn dispatch(self: &Arc<Self>, s: &mut State) {
+        let mut skipped = 0;
+        while skipped < s.rotation.len() && !s.global_full() {
+            let peer = match s.rotation.pop_front() {
+                Some(p) => p,
+                None => break,
+            };
+            if s.peer_full(&peer) {
+                s.rotation.push_back(peer);
+                skipped += 1;
+                continue;
+            }
+
+            let p = s.peers.entry(peer).or_default();
+            let tx = match p.waiting.pop_front() {
+                Some(tx) => tx,
+                None => continue,
+            };
+            if !p.waiting.is_empty() {
+                s.rotation.push_back(peer);
+            }
+            s.queued -= 1;
+            skipped = 0;
+
+            s.active += 1;
+            s.peers.entry(peer).or_default().active += 1;
+            let permit = Permit {
+                queue: Some(Arc::clone(self)),
+                peer,
+            };
+            // The request was cancelled while it waited. Its slot is taken
+            // back here, dropping the permit would lock the state again.
+            if let Err(mut permit) = tx.send(permit) {
+                permit.queue = None;
+                s.active -= 1;
+                s.peers.entry(peer).or_default().active -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn ready(admission: Result<Admission, LandohError>) -> Permit {
+    match admission {
+        Ok(Admission::Ready(p)) => p,
+        other => panic!("not admitted: {:?}", other),
+    }
+}
+
+#[cfg(test)]
+fn queued(admission: Result<Admission, LandohError>) -> oneshot::Receiver<Permit> {
+    match admission {
+        Ok(Admission::Queued { permit, .. }) => permit,
+        other => panic!("not queued: {:?}", other),
+    }
+}
+
+#[test]
+fn test_transfer_queue_round_robin() {
+    let queue = Arc::new(TransferQueue::default());
+    let limits = TransferLimits {
+        global: 2,
+        per_peer: 2,
+        queue: 4,
+    };
+    let a: IpAddr = "192.168.0.2".parse().unwrap();
+    let b: IpAddr = "192.168.0.3".parse().unwrap();
+
+    let first = ready(queue.acquire(a, limits));
+    let second = ready(queue.acquire(a, limits));
+
+    // `a` asks for more before `b` shows up, but `b` is served in between.
+    let mut a1 = queued(queue.acquire(a, limits));
+    let mut a2 = queued(queue.acquire(a, limits));
+    let mut b1 = queued(queue.acquire(b, limits));
+    let cancelled = queued(queue.acquire(b, limits));
+    assert!(matches!(
+        queue.acquire(b, limits),
+        Err(LandohError::Busy(_))
+    ));
+
+    drop(first);
+    let a1 = a1.try_recv().unwrap();
+    assert!(a2.try_recv().is_err());
+
+    drop(second);
+    let b1 = b1.try_recv().unwrap();
+    assert!(a2.try_recv().is_err());
+
+    drop(b1);
+    let a2 = a2.try_recv().unwrap();
+
+    // A cancelled request makes room in the queue and is skipped.
+    drop(cancelled);
+    let mut b2 = queued(queue.acquire(b, limits));
+    drop(a1);
+    let b2 = b2.try_recv().unwrap();
+
+    drop(b2);
+    drop(a2);
+    let s = queue.state.lock().unwrap();
+    assert_eq!((s.active, s.queued), (0, 0));
+    assert!(s.peers.is_empty());
+}
+
+#[test]
+fn test_transfer_queue_per_peer() {
+    let queue = Arc::new(TransferQueue::default());
+    let mut limits = TransferLimits {
+        global: 0,
+        per_peer: 1,
+        queue: 0,
+    };
+    let a: IpAddr = "192.168.0.2".parse().unwrap();
+    let b: IpAddr = "192.168.0.3".parse().unwrap();
+
+    let first = ready(queue.acquire(a, limits));
+    let mut waiting = queued(queue.acquire(a, limits));
+    let other = ready(queue.acquire(b, limits));
+    assert!(waiting.try_recv().is_err());
+
+    // Raising the limit lets the waiting request start with the next one.
+    limits.per_peer = 3;
+    let third = ready(queue.acquire(a, limits));
+    let second = waiting.try_recv().unwrap();
+
+    drop((first, second, third, other));
+    assert!(queue.state.lock().unwrap().peers.is_empty());
+}
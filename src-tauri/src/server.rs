@@ -1,29 +1,61 @@
-use std::sync::Arc;
-use std::{error::Error, fs::File, io::Read, net::SocketAddr, path::PathBuf, pin::Pin};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+};
 
+use bytes::{Bytes, BytesMut};
 use data_encoding::HEXUPPER;
-use log::{error, warn};
+use log::{error, info, warn};
 use ring::digest::{Context, SHA256};
+use socket2::{Domain, Protocol, Socket, Type};
 
 use walkdir::WalkDir;
 
+use tokio::net::TcpListener;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio_stream::{wrappers::ReceiverStream, Stream};
-use tonic::{transport::Server as tServer, Status};
+use tonic::{transport::Server as tServer, Status, Streaming};
 use tonic::{Request, Response};
 
-use crate::app::Config;
-use crate::model::CHUNK_SIZE;
+use crate::access::{unmapped, Access, PasswordCache, Requester};
+use crate::app::{hash_cache_path, Config};
+use crate::cache::{cached_hash, hash_shared, HashCache};
+use crate::chunk::clamp_chunk_size;
+use crate::client::part_path;
+use crate::compression::{is_compressed, Compression};
+use crate::consent::{
+    Approval, ApprovalRequest, Approvals, FileOffer, IncomingFiles, APPROVAL_TIMEOUT,
+};
+use crate::error::LandohError;
+use crate::group::{GroupKey, Membership};
+use crate::limit::{Limiter, Upload};
+use crate::queue::{Admission, TransferQueue};
+use crate::tls::{self, fingerprint, Identity, KnownPeers};
 
 use crate::pb::{
-    get_file_response::FileResponse, lan_doh_server, lan_doh_server::LanDoh, FileMetaData,
-    GetDirectoryRequest, GetDirectoryResponse, GetFileRequest, GetFileResponse,
-    ListDirectoriesRequest, ListDirectoriesResponse,
+    self, get_file_response::FileResponse, lan_doh_server, lan_doh_server::LanDoh,
+    send_file_request::SendRequest, CompressedChunk, FileMetaData, GetDirectoryRequest,
+    GetDirectoryResponse, GetFileRequest, GetFileResponse, ListDirectoriesRequest,
+    ListDirectoriesResponse, SendFileRequest, SendFileResponse,
 };
 
 pub use crate::pb::Directory;
-use crate::shorten_path;
 
+/// Upper bound for the data queued per GetFile stream.
+const STREAM_BUFFER: usize = 4 * 1024 * 1024;
+/// Number of chunks the read buffer of a stream has room for before a new
+/// one is allocated.
+const BUFFERED_CHUNKS: usize = 8;
+/// Number of received chunks of a SendFile stream waiting to be written.
+const RECEIVE_BUFFER: usize = 16;
+
+// The discovery messages are not part of the service, so unused here.
+#[allow(dead_code)]
 mod pb_proto {
     include!("pb.rs");
 
@@ -34,23 +66,78 @@ mod pb_proto {
 #[derive(Debug)]
 pub struct Server {
     config: Arc<tokio::sync::Mutex<Config>>,
+    hash_cache: Arc<Mutex<HashCache>>,
+    limiter: Arc<Limiter>,
+    queue: Arc<TransferQueue>,
+    identity: Identity,
+    /// Tells which peer presented a client certificate.
+    known_peers: Arc<KnownPeers>,
+    passwords: PasswordCache,
+    /// Only members of this group are served.
+    group: Option<Arc<GroupKey>>,
+    /// Asks the owner about unknown peers if `Config::ask_before_serving` is set.
+    approvals: Arc<Approvals>,
+    /// Asks the owner whether to accept files peers send.
+    incoming: Arc<IncomingFiles>,
 }
 
 impl Server {
-    pub fn new(config: Arc<tokio::sync::Mutex<Config>>) -> Self {
-        Server { config }
+    pub fn new(
+        config: Arc<tokio::sync::Mutex<Config>>,
+        limiter: Arc<Limiter>,
+        identity: Identity,
+        known_peers: Arc<KnownPeers>,
+    ) -> Self {
+        let hash_cache = match hash_cache_path() {
+            Ok(p) => HashCache::load(p),
+            Err(err) => {
+                warn!("hashes will not be persisted: {}", err);
+                HashCache::default()
+            }
+        };
+
+        Server {
+            config,
+            hash_cache: Arc::new(Mutex::new(hash_cache)),
+            limiter,
+            queue: Arc::new(TransferQueue::default()),
+            identity,
+            known_peers,
+            passwords: PasswordCache::default(),
+            group: None,
+            approvals: Arc::new(Approvals::default()),
+            incoming: Arc::new(IncomingFiles::default()),
+        }
     }
 
-    pub async fn serve(self, addr: SocketAddr) -> Result<(), Box<dyn Error>> {
+    pub fn with_group(mut self, group: Arc<GroupKey>) -> Self {
+        self.group = Some(group);
+        self
+    }
+
+    pub fn with_approvals(mut self, approvals: Arc<Approvals>) -> Self {
+        self.approvals = approvals;
+        self
+    }
+
+    /// Without an owner subscribed to `incoming`, sent files are refused.
+    pub fn with_incoming(mut self, incoming: Arc<IncomingFiles>) -> Self {
+        self.incoming = incoming;
+        self
+    }
+
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), LandohError> {
         let reflection_service = tonic_reflection::server::Builder::configure()
             .register_encoded_file_descriptor_set(pb_proto::FILE_DESCRIPTOR_SET)
             .build()
-            .unwrap();
+            .map_err(|err| LandohError::Internal(err.to_string()))?;
 
+        let incoming = tls::incoming(TcpListener::from_std(bind(addr)?)?, &self.identity)?;
         tServer::builder()
+            .layer(tonic::service::interceptor(Membership(self.group.clone())))
             .add_service(lan_doh_server::LanDohServer::new(self))
             .add_service(reflection_service)
-            .serve(addr)
+            .serve_with_incoming(incoming)
             .await?;
         Ok(())
     }
@@ -68,6 +155,254 @@ impl Server {
             None => None,
         }
     }
+
+    /// Fails unless the sender of `request` may access the share `name`.
+    async fn check_access<T>(
+        &self,
+        request: &Request<T>,
+        name: &str,
+        password: Option<String>,
+    ) -> Result<(), LandohError> {
+        let access = self.config.lock().await.access_for(name);
+        let requester = Requester {
+            password,
+            ..requester(request)
+        };
+        let requester = match access {
+            Access::Password { .. } if self.passwords.contains(name, &access, &requester) => {
+                return Ok(());
+            }
+            // PBKDF2 is slow on purpose, so it is kept off the runtime.
+            Access::Password { .. } => {
+                let known_peers = Arc::clone(&self.known_peers);
+                let (access, requester, allowed) = tokio::task::spawn_blocking(move || {
+                    let allowed = access.allows(&requester, &known_peers);
+                    (access, requester, allowed)
+                })
+                .await?;
+                if allowed {
+                    self.passwords.insert(name, &access, &requester);
+                    return Ok(());
+                }
+                requester
+            }
+            _ if access.allows(&requester, &self.known_peers) => return Ok(()),
+            _ => requester,
+        };
+
+        warn!(
+            "denying access to {:?} for {:?} ({:?})",
+            name, requester.ip, requester.fingerprint
+        );
+        Err(LandohError::AccessDenied(name.to_string()))
+    }
+
+    /// Fails unless the owner does not ask before serving, or approves the
+    /// sender of `request` in time.
+    async fn check_approval<T>(
+        &self,
+        request: &Request<T>,
+        share: &str,
+    ) -> Result<(), LandohError> {
+        let approval = self.approval_request(&requester(request), share.to_string());
+        let peer = approval.peer.clone();
+
+        {
+            let c = self.config.lock().await;
+            if !c.ask_before_serving {
+                return Ok(());
+            }
+            match c.remembered_peers.get(&peer) {
+                Some(true) => return Ok(()),
+                Some(false) => return Err(LandohError::AccessDenied(share.to_string())),
+                None => {}
+            }
+        }
+
+        if approved(self.approvals.ask(approval)).await {
+            return Ok(());
+        }
+
+        warn!("{} was not approved to access {:?}", peer, share);
+        Err(LandohError::AccessDenied(share.to_string()))
+    }
+
+    /// What the owner is asked about `requester`. Peers are told apart by
+    /// their certificate if they present one.
+    fn approval_request(&self, requester: &Requester, share: String) -> ApprovalRequest {
+        ApprovalRequest {
+            id: requester
+                .fingerprint
+                .as_ref()
+                .and_then(|fp| self.known_peers.peer_id(fp)),
+            peer: match (&requester.fingerprint, requester.ip) {
+                (Some(fp), _) => fp.clone(),
+                (None, Some(ip)) => ip.to_string(),
+                (None, None) => String::new(),
+            },
+            ip: requester.ip.map(|ip| ip.to_string()),
+            share,
+        }
+    }
+
+    /// Writes the chunks of a SendFile stream to `part` and checks them
+    /// against the metadata sent up front.
+    async fn receive(
+        &self,
+        stream: &mut Streaming<SendFileRequest>,
+        meta: &FileMetaData,
+        part: &Path,
+    ) -> Result<(), LandohError> {
+        let (tx, rx) = mpsc::channel(RECEIVE_BUFFER);
+        let writer = {
+            let part = part.to_path_buf();
+            tokio::task::spawn_blocking(move || write_chunks(&part, rx))
+        };
+        let streamed = self.stream_chunks(stream, meta, tx).await;
+
+        // A writer that failed stops taking chunks, which is why it is
+        // asked first.
+        let hash = writer.await??;
+        let written = streamed?;
+        if written != meta.file_size {
+            return Err(Status::data_loss(format!(
+                "short file {:?}: expected {} bytes, got {}",
+                meta.path, meta.file_size, written
+            ))
+            .into());
+        }
+
+        if hash != meta.hash {
+            return Err(LandohError::Integrity {
+                path: meta.path.clone(),
+                expected: meta.hash.clone(),
+                actual: hash,
+            });
+        }
+        Ok(())
+    }
+
+    /// Passes the chunks of a SendFile stream on to `tx` within the download
+    /// limit and returns how many bytes there were.
+    async fn stream_chunks(
+        &self,
+        stream: &mut Streaming<SendFileRequest>,
+        meta: &FileMetaData,
+        tx: Sender<Bytes>,
+    ) -> Result<u64, LandohError> {
+        let mut written: u64 = 0;
+
+        while let Some(m) = stream.message().await? {
+            let chunk = match m.send_request {
+                Some(SendRequest::Chunk(c)) => c,
+                _ => {
+                    return Err(LandohError::InvalidRequest(
+                        "SendFile: metadata sent twice".to_string(),
+                    ))
+                }
+            };
+            written += chunk.len() as u64;
+            if written > meta.file_size {
+                return Err(Status::data_loss(format!(
+                    "{:?} is larger than the announced {} bytes",
+                    meta.path, meta.file_size
+                ))
+                .into());
+            }
+
+            let wait = self.limiter.download(chunk.len() as u64);
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+            if tx.send(chunk).await.is_err() {
+                break;
+            }
+        }
+        Ok(written)
+    }
+}
+
+/// Writes the chunks from `rx` to the new file `part` and returns their hash.
+/// This blocks on writes, so it has to run on the blocking pool.
+fn write_chunks(part: &Path, mut rx: Receiver<Bytes>) -> io::Result<String> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(part)?;
+    let mut context = Context::new(&SHA256);
+    while let Some(chunk) = rx.blocking_recv() {
+        context.update(&chunk);
+        file.write_all(&chunk)?;
+    }
+    Ok(HEXUPPER.encode(context.finish().as_ref()))
+}
+
+/// Moves `part` to `path` unless a file turned up there in the meantime. A
+/// hard link fails instead of replacing what is there; file systems without
+/// links fall back to checking right before renaming.
+fn move_new(part: &Path, path: &Path) -> io::Result<()> {
+    match fs::hard_link(part, path) {
+        Ok(()) => {
+            if let Err(err) = fs::remove_file(part) {
+                warn!("failed to remove {:?}: {}", part, err);
+            }
+            Ok(())
+        }
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Err(err),
+        Err(_) if fs::symlink_metadata(path).is_ok() => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("file already exists: {:?}", path),
+        )),
+        Err(_) => fs::rename(part, path),
+    }
+}
+
+/// Whether the owner approves in time.
+async fn approved(approval: Approval) -> bool {
+    match approval {
+        Approval::Approved => true,
+        Approval::Denied => false,
+        Approval::Pending(answer) => matches!(
+            tokio::time::timeout(APPROVAL_TIMEOUT, answer).await,
+            Ok(Ok(true))
+        ),
+    }
+}
+
+/// Listens on `addr`. The unspecified IPv4 address stands for every address,
+/// so IPv6 peers are served on the same port where the system allows it.
+fn bind(addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+    if addr.ip() == IpAddr::V4(Ipv4Addr::UNSPECIFIED) {
+        match bind_dual_stack(addr.port()) {
+            Ok(l) => return Ok(l),
+            Err(err) => warn!("serving IPv4 only: {}", err),
+        }
+    }
+
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+fn bind_dual_stack(port: u16) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    // Like tokio does, so a restarted server does not wait for old
+    // connections to time out.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port).into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// The address and certificate a request was sent from.
+pub(crate) fn requester<T>(request: &Request<T>) -> Requester {
+    Requester {
+        ip: request.remote_addr().map(|a| unmapped(a.ip())),
+        fingerprint: request
+            .peer_certs()
+            .and_then(|certs| certs.first().map(|c| fingerprint(c.get_ref()))),
+        password: None,
+    }
 }
 
 #[tonic::async_trait]
@@ -75,21 +410,25 @@ impl LanDoh for Server {
     type GetFileStream = Pin<Box<dyn Stream<Item = Result<GetFileResponse, Status>> + Send>>;
     async fn list_directories(
         &self,
-        _request: Request<ListDirectoriesRequest>,
+        request: Request<ListDirectoriesRequest>,
     ) -> Result<Response<ListDirectoriesResponse>, Status> {
+        let requester = requester(&request);
+        let c = self.config.lock().await;
         Ok(Response::new(ListDirectoriesResponse {
-            dirs: self
-                .config
-                .lock()
-                .await
+            dirs: c
                 .shared_directories
                 .iter()
+                .filter(|d| {
+                    c.access_for(&d.name)
+                        .is_visible(&requester, &self.known_peers)
+                })
                 .map(|d| Directory {
                     name: d.name.clone(),
                     paths: d
                         .paths
                         .iter()
-                        .map(|p| shorten_path(d.name.clone(), p.clone()))
+                        .filter_map(|p| Path::new(p).file_name())
+                        .map(|n| n.to_string_lossy().to_string())
                         .collect(),
                 })
                 .collect(),
@@ -100,161 +439,755 @@ impl LanDoh for Server {
         &self,
         request: Request<GetDirectoryRequest>,
     ) -> Result<Response<GetDirectoryResponse>, Status> {
+        self.check_access(
+            &request,
+            &request.get_ref().name,
+            request.get_ref().password.clone(),
+        )
+        .await?;
+        self.check_approval(&request, &request.get_ref().name)
+            .await?;
+        let r = request.into_inner();
+
+        let dir = match self.get_dir(&r.name).await {
+            Some(d) => d,
+            None => {
+                warn!("{:?} / {:?}", r, self);
+                return Err(LandohError::InvalidRequest(format!(
+                    "GetDir: invalid item: {}",
+                    r.name
+                ))
+                .into());
+            }
+        };
+
+        let cache = Arc::clone(&self.hash_cache);
+
+        let files = tokio::task::spawn_blocking(move || list_share(&dir, &cache))
+            .await
+            .map_err(LandohError::from)?;
+
+        Ok(Response::new(GetDirectoryResponse { files: files }))
+    }
+
+    async fn get_file(
+        &self,
+        request: Request<GetFileRequest>,
+    ) -> Result<Response<Self::GetFileStream>, Status> {
+        let peer = requester(&request).ip;
+        let share = requested_share(&request.get_ref().path);
+        self.check_access(&request, &share, request.get_ref().password.clone())
+            .await?;
+        self.check_approval(&request, &share).await?;
         let r = request.into_inner();
 
-        let dir_res = self.get_dir(&r.name).await;
+        let (path, chunk_size, transfers) = {
+            let c = self.config.lock().await;
+            let chunk_size = match r.chunk_size {
+                Some(s) => s as usize,
+                None => c.chunk_size,
+            };
+            (
+                resolve_shared_path(&c.shared_directories, &r.path),
+                clamp_chunk_size(chunk_size),
+                c.transfers,
+            )
+        };
 
-        if dir_res == None {
-            warn!("{:?} / {:?}", r, self);
-            return Err(Status::invalid_argument(format!(
-                "GetDir: invalid item: {}",
-                r.name
-            )));
+        let path = match path {
+            Ok(p) => p,
+            Err(err) => {
+                warn!("refusing GetFile for {:?}: {}", &r.path, err);
+                return Err(err.into());
+            }
+        };
+
+        let peer_ip = peer.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let admission = match self.queue.acquire(peer_ip, transfers) {
+            Ok(a) => a,
+            Err(err) => {
+                warn!(
+                    "refusing GetFile for {:?} from {}: {}",
+                    &r.path, peer_ip, err
+                );
+                return Err(err.into());
+            }
+        };
+
+        let (tx, rx): (
+            Sender<Result<GetFileResponse, Status>>,
+            Receiver<Result<GetFileResponse, Status>>,
+        ) = mpsc::channel((STREAM_BUFFER / chunk_size).max(4));
+
+        let options = SendOptions {
+            offset: r.offset,
+            length: r.length,
+            chunk_size,
+            compression: if is_compressed(&path) {
+                Compression::None
+            } else {
+                Compression::from(r.compression())
+            },
+            upload: self.limiter.upload(peer),
+        };
+
+        let cache = Arc::clone(&self.hash_cache);
+
+        tokio::spawn(async move {
+            let permit = match admission {
+                Admission::Ready(p) => p,
+                Admission::Queued { position, permit } => {
+                    let _ = tx
+                        .send(Ok(GetFileResponse {
+                            file_response: Some(FileResponse::QueuePosition(position as u32)),
+                        }))
+                        .await;
+                    // A client that cancels while it waits closes the stream.
+                    // Dropping the permit's receiver then takes the request
+                    // out of the queue.
+                    tokio::select! {
+                        p = permit => match p {
+                            Ok(p) => p,
+                            Err(_) => return,
+                        },
+                        _ = tx.closed() => return,
+                    }
+                }
+            };
+
+            send_file(path, options, cache, tx).await;
+            drop(permit);
+        });
+
+        let output_stream: ReceiverStream<Result<GetFileResponse, Status>> =
+            ReceiverStream::new(rx);
+
+        Ok(Response::new(Box::pin(output_stream) as Self::GetFileStream))
+    }
+
+    async fn send_file(
+        &self,
+        request: Request<Streaming<SendFileRequest>>,
+    ) -> Result<Response<SendFileResponse>, Status> {
+        let requester = requester(&request);
+        let mut stream = request.into_inner();
+
+        let meta = match stream.message().await? {
+            Some(SendFileRequest {
+                send_request: Some(SendRequest::Meta(m)),
+            }) => m,
+            _ => {
+                return Err(LandohError::InvalidRequest(
+                    "SendFile: metadata has to be sent first".to_string(),
+                )
+                .into())
+            }
+        };
+
+        let destination = self.config.lock().await.destination.clone();
+        let path = match received_path(&destination, &meta.path) {
+            Ok(p) => p,
+            Err(err) => {
+                warn!("refusing SendFile for {:?}: {}", &meta.path, err);
+                return Err(err.into());
+            }
+        };
+        // Received files never replace what is there already.
+        if path.exists() {
+            return Err(LandohError::from(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("file already exists: {:?}", meta.path),
+            ))
+            .into());
         }
 
-        let dir = dir_res.unwrap();
+        let request = self.approval_request(&requester, meta.path.clone());
+        let peer = request.peer.clone();
+        let offer = FileOffer {
+            id: request.id,
+            peer: request.peer,
+            ip: request.ip,
+            path: meta.path.clone(),
+            size: meta.file_size,
+            hash: meta.hash.clone(),
+        };
+        if !approved(self.incoming.ask(offer)).await {
+            warn!("{} was not allowed to send {:?}", peer, meta.path);
+            return Err(LandohError::AccessDenied(meta.path).into());
+        }
 
-        let mut files: Vec<FileMetaData> = vec![];
-        dir.paths.iter().map(|p| PathBuf::from(p)).for_each(|path| {
-            if !&path.exists() {
-                return;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(LandohError::from)?;
+        }
+        let part = part_path(&path);
+        if let Err(err) = self.receive(&mut stream, &meta, &part).await {
+            warn!("failed to receive {:?} from {}: {}", meta.path, peer, err);
+            // Another transfer of the same file may be writing to it.
+            if !matches!(&err, LandohError::Filesystem(e) if e.kind() == io::ErrorKind::AlreadyExists)
+            {
+                let _ = fs::remove_file(&part);
             }
+            return Err(err.into());
+        }
+        // The owner may have taken a while to answer.
+        if let Err(err) = move_new(&part, &path) {
+            warn!("failed to store {:?} from {}: {}", meta.path, peer, err);
+            let _ = fs::remove_file(&part);
+            return Err(LandohError::from(err).into());
+        }
 
-            for f in WalkDir::new(&path) {
-                let e = f.unwrap();
+        let stored = path
+            .strip_prefix(&destination)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .to_string();
+        info!("received {:?} from {}", stored, peer);
+        Ok(Response::new(SendFileResponse { path: stored }))
+    }
+}
+
+/// The share a `share/sub/file` request refers to.
+fn requested_share(request: &str) -> String {
+    request
+        .split(['/', '\\'])
+        .find(|p| !p.is_empty() && *p != ".")
+        .unwrap_or_default()
+        .to_string()
+}
 
-                let m = e.metadata().unwrap();
-                if m.is_dir() {
+/// Lists the files of a share and hashes them. Blocking.
+fn list_share(dir: &Directory, cache: &Mutex<HashCache>) -> Vec<FileMetaData> {
+    let mut files: Vec<FileMetaData> = vec![];
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    dir.paths.iter().map(PathBuf::from).for_each(|path| {
+        let root = match path.canonicalize() {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+
+        // Unreadable entries are left out of the listing instead of
+        // failing the whole request.
+        for f in WalkDir::new(&path) {
+            let e = match f {
+                Ok(e) => e,
+                Err(err) => {
+                    warn!("skipping unreadable entry: {}", err);
                     continue;
                 }
+            };
+
+            if e.file_type().is_dir() {
+                continue;
+            }
 
-                let p = shorten_path(dir.name.clone(), String::from(e.path().to_str().unwrap()));
+            // Symlinks are only followed as long as they stay inside
+            // the share, like GetFile does.
+            let target = match e.path().canonicalize() {
+                Ok(t) if t.starts_with(&root) => t,
+                Ok(_) => {
+                    warn!("skipping {:?}: it leads out of the share", e.path());
+                    continue;
+                }
+                Err(err) => {
+                    warn!("skipping {:?}: {}", e.path(), err);
+                    continue;
+                }
+            };
+            let m = match target.metadata() {
+                Ok(m) => m,
+                Err(err) => {
+                    warn!("skipping {:?}: {}", e.path(), err);
+                    continue;
+                }
+            };
+            if !m.is_file() {
+                continue;
+            }
 
-                files.push(FileMetaData {
-                    file_size: m.len(),
-                    hash: "none".to_string(),
-                    path: p,
-                });
+            // A share is the union of its paths. When several of them
+            // contain the same relative path, the one listed first wins.
+            let rel = match e.path().strip_prefix(&path) {
+                Ok(r) => r.to_path_buf(),
+                Err(_) => continue,
+            };
+            if !seen.insert(rel.clone()) {
+                continue;
             }
-        });
 
-        Ok(Response::new(GetDirectoryResponse { files: files }))
+            let hash = match hash_shared(cache, &target, &m) {
+                Ok(h) => h,
+                Err(err) => {
+                    warn!("failed to hash {:?}: {}", e.path(), err);
+                    "none".to_string()
+                }
+            };
+
+            files.push(FileMetaData {
+                file_size: m.len(),
+                hash,
+                path: Path::new(&dir.name).join(&rel).to_string_lossy().to_string(),
+            });
+        }
+    });
+
+    if let Err(err) = cache.lock().unwrap_or_else(PoisonError::into_inner).save() {
+        warn!("failed to save hash cache: {}", err);
     }
+    files
+}
 
-    async fn get_file(
-        &self,
-        request: Request<GetFileRequest>,
-    ) -> Result<Response<Self::GetFileStream>, Status> {
-        let r = request.into_inner();
+/// Maps a requested `share/sub/file` path onto the file system, trying the
+/// share's paths in order like `get_directory` does. The result has to be an
+/// existing file inside that path after resolving `..` and symlinks, so peers
+/// cannot reach anything that was not listed.
+fn resolve_shared_path(dirs: &[Directory], request: &str) -> Result<PathBuf, LandohError> {
+    let invalid = || LandohError::InvalidRequest(format!("invalid item: {:?}", request));
 
-        let mut path = PathBuf::from(r.path.clone());
+    // Peers on other platforms may use either separator.
+    let normalized = request.replace('\\', "/");
+    if normalized.starts_with('/') {
+        return Err(invalid());
+    }
 
-        let dir_name: PathBuf = path.iter().take(1).collect();
+    let mut parts = normalized.split('/').filter(|p| !p.is_empty() && *p != ".");
 
-        let mut shared_dir = false;
+    let name = parts.next().ok_or_else(invalid)?;
+    let dir = dirs.iter().find(|d| d.name == name).ok_or_else(invalid)?;
 
-        {
-            self.config
-                .lock()
-                .await
-                .shared_directories
-                .iter()
-                .for_each(|d| {
-                    if d.name == dir_name.to_str().unwrap().to_string() {
-                        shared_dir = true;
-                        path = PathBuf::from(d.paths[0].clone())
-                            .parent()
-                            .unwrap()
-                            .join(&path);
-                    }
-                });
+    let mut rel = PathBuf::new();
+    for part in parts {
+        let mut c = Path::new(part).components();
+        match (c.next(), c.next()) {
+            (Some(Component::Normal(_)), None) => rel.push(part),
+            _ => return Err(invalid()),
         }
+    }
+    if rel.as_os_str().is_empty() {
+        return Err(invalid());
+    }
+
+    for root in &dir.paths {
+        let root = match Path::new(root).canonicalize() {
+            Ok(r) => r,
+            Err(_) => continue,
+        };
 
-        if !shared_dir {
-            return Err(Status::invalid_argument(format!(
-                "invalid item: {:?}",
-                &path
-            )));
+        let path = root.join(&rel);
+        match fs::symlink_metadata(&path) {
+            Ok(m) if !m.is_dir() => {}
+            _ => continue,
         }
 
-        if !path.exists() {
-            return Err(Status::invalid_argument(format!(
-                "item is marked for sharing but could not be found: {:?}",
-                &path
-            )));
+        let path = path.canonicalize()?;
+        if !path.starts_with(&root) || !path.is_file() {
+            return Err(invalid());
         }
+        return Ok(path);
+    }
 
-        let (tx, rx): (
-            Sender<Result<GetFileResponse, Status>>,
-            Receiver<Result<GetFileResponse, Status>>,
-        ) = mpsc::channel(128);
+    Err(LandohError::Filesystem(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "item is marked for sharing but could not be found: {:?}",
+            request
+        ),
+    )))
+}
 
-        tokio::spawn(async move {
-            send_file(&path.to_str().unwrap(), tx).await;
-        });
+/// Where a file sent as `request` is stored. Only plain relative paths are
+/// accepted, so peers cannot write anywhere outside `destination`.
+pub(crate) fn received_path(destination: &Path, request: &str) -> Result<PathBuf, LandohError> {
+    let invalid = || LandohError::InvalidRequest(format!("invalid path: {:?}", request));
 
-        let output_stream: ReceiverStream<Result<GetFileResponse, Status>> =
-            ReceiverStream::new(rx);
+    let normalized = request.replace('\\', "/");
+    if normalized.starts_with('/') {
+        return Err(invalid());
+    }
 
-        Ok(Response::new(Box::pin(output_stream) as Self::GetFileStream))
+    let mut rel = PathBuf::new();
+    for part in normalized.split('/').filter(|p| !p.is_empty() && *p != ".") {
+        let mut c = Path::new(part).components();
+        match (c.next(), c.next()) {
+            (Some(Component::Normal(_)), None) => rel.push(part),
+            _ => return Err(invalid()),
+        }
+    }
+    if rel.as_os_str().is_empty() {
+        return Err(invalid());
+    }
+    Ok(destination.join(rel))
+}
+
+/// How a GetFile stream is sent, as negotiated in `get_file`.
+#[derive(Debug)]
+pub(crate) struct SendOptions {
+    offset: u64,
+    length: Option<u64>,
+    chunk_size: usize,
+    compression: Compression,
+    upload: Upload,
+}
+
+/// What a `ChunkReader` did with the next chunk of its file.
+enum Step {
+    /// A chunk of the requested range, ready to be sent.
+    Chunk(FileResponse),
+    /// A chunk outside of the range, only read to hash it.
+    Skipped,
+    Done,
+}
+
+/// Reads the file of a GetFile stream one chunk at a time. Chunks are split
+/// off a shared buffer, which is reused once the chunks taken from it have
+/// been sent. Chunks that `compression` makes smaller are compressed, all
+/// others are sent as they are. Every call blocks on the file, so it has to
+/// run on the blocking pool.
+struct ChunkReader {
+    file: File,
+    size: u64,
+    offset: u64,
+    end: u64,
+    chunk_size: usize,
+    compression: Compression,
+    known_hash: Option<String>,
+    context: Context,
+    buffer: BytesMut,
+    /// Bytes read so far, including those skipped by seeking.
+    position: u64,
+}
+
+impl ChunkReader {
+    fn open(
+        path: &Path,
+        options: &SendOptions,
+        known_hash: Option<String>,
+    ) -> Result<Self, LandohError> {
+        let mut file = File::open(path).map_err(|err| {
+            error!("{:?}: {:?}", path, err);
+            LandohError::from(err)
+        })?;
+        let size = file.metadata()?.len();
+
+        if options.offset > size {
+            return Err(Status::out_of_range(format!(
+                "offset {} exceeds file size {}",
+                options.offset, size
+            ))
+            .into());
+        }
+
+        let end = match options.length {
+            Some(l) => size.min(options.offset.saturating_add(l)),
+            None => size,
+        };
+
+        // The trailing hash always covers the whole file. With a cached hash the
+        // reader can seek straight to the requested range, otherwise the bytes the
+        // client already has are read and hashed but not sent again.
+        let mut position = 0;
+        if known_hash.is_some() && options.offset > 0 {
+            file.seek(SeekFrom::Start(options.offset))
+                .map_err(|err| LandohError::Internal(format!("failed to seek file: {}", err)))?;
+            position = options.offset;
+        }
+
+        Ok(ChunkReader {
+            file,
+            size,
+            offset: options.offset,
+            end,
+            chunk_size: options.chunk_size,
+            compression: options.compression,
+            known_hash,
+            context: Context::new(&SHA256),
+            buffer: BytesMut::with_capacity(options.chunk_size * BUFFERED_CHUNKS),
+            position,
+        })
+    }
+
+    fn next(&mut self) -> Result<Step, LandohError> {
+        if self.known_hash.is_some() && self.position >= self.end {
+            return Ok(Step::Done);
+        }
+        let mut chunk = (self.size - self.position).min(self.chunk_size as u64) as usize;
+        if self.position < self.offset {
+            chunk = chunk.min((self.offset - self.position) as usize);
+        } else if self.position < self.end {
+            chunk = chunk.min((self.end - self.position) as usize);
+        }
+
+        self.buffer.resize(chunk, 0);
+        let count = self
+            .file
+            .read(&mut self.buffer)
+            .map_err(|err| LandohError::Internal(format!("failed to read file: {}", err)))?;
+        if count == 0 {
+            return Ok(Step::Done);
+        }
+        self.buffer.truncate(count);
+        self.context.update(&self.buffer);
+
+        let in_range = self.position >= self.offset && self.position < self.end;
+        self.position += count as u64;
+        if !in_range {
+            self.buffer.clear();
+            return Ok(Step::Skipped);
+        }
+
+        Ok(Step::Chunk(match self.compression.compress(&self.buffer) {
+            Some(data) => {
+                self.buffer.clear();
+                FileResponse::CompressedChunk(CompressedChunk {
+                    compression: pb::Compression::from(self.compression).into(),
+                    data: data.into(),
+                    size: count as u32,
+                })
+            }
+            None => FileResponse::Chunk(self.buffer.split().freeze()),
+        }))
+    }
+
+    fn hash(self) -> String {
+        match self.known_hash {
+            Some(h) => h,
+            None => HEXUPPER.encode(self.context.finish().as_ref()),
+        }
     }
 }
 
-pub async fn send_file(path: &str, tx: Sender<Result<GetFileResponse, Status>>) {
-    let mut reader: File = match File::open(&path) {
-        Ok(f) => f,
+/// Streams the requested range of `path` to `tx`, followed by the file's
+/// metadata. The file is read in chunks on the blocking pool, one chunk per
+/// blocking call, so a slow peer only holds up a blocking thread while a
+/// chunk is read. Sending waits for the upload limits of `options`.
+pub(crate) async fn send_file(
+    path: PathBuf,
+    options: SendOptions,
+    cache: Arc<Mutex<HashCache>>,
+    tx: Sender<Result<GetFileResponse, Status>>,
+) {
+    let name = path.to_string_lossy().to_string();
+    let opened = tokio::task::spawn_blocking(move || {
+        let known_hash = cached_hash(&cache, &path);
+        ChunkReader::open(&path, &options, known_hash).map(|r| (r, options.upload))
+    })
+    .await
+    .map_err(LandohError::from)
+    .and_then(|r| r);
+    let (mut reader, upload) = match opened {
+        Ok(r) => r,
         Err(err) => {
-            error!("{:?}: {:?}", &path, err);
-            let e = Err(Status::internal(format!(
-                "ERROR: failed to open file: {:?}",
-                err
-            )));
-            let _ = tx.send(e.clone()).await;
+            let _ = tx.send(Err(err.into())).await;
             return;
         }
     };
-    let mut context = Context::new(&SHA256);
-    let size = reader.metadata().unwrap().len();
-    let mut start_bytes = 0;
+
     loop {
-        let chunk: usize;
-        if size - start_bytes >= CHUNK_SIZE as u64 {
-            chunk = CHUNK_SIZE;
-        } else {
-            chunk = (size - start_bytes) as usize;
-        }
-        let mut buffer = vec![0; chunk];
-        let count = match reader.read(&mut buffer) {
-            Ok(r) => r,
+        let read = tokio::task::spawn_blocking(move || {
+            let step = reader.next();
+            (reader, step)
+        })
+        .await;
+        let file_response = match read {
+            Ok((r, step)) => {
+                reader = r;
+                match step {
+                    Ok(Step::Chunk(c)) => c,
+                    Ok(Step::Skipped) => continue,
+                    Ok(Step::Done) => break,
+                    Err(err) => {
+                        let _ = tx.send(Err(err.into())).await;
+                        return;
+                    }
+                }
+            }
             Err(err) => {
-                let _ = tx
-                    .send(Err(Status::internal(format!(
-                        "failed to read file: {}",
-                        err
-                    ))))
-                    .await;
+                let _ = tx.send(Err(LandohError::from(err).into())).await;
                 return;
             }
         };
-        if count == 0 {
-            break;
+
+        let wire_size = match &file_response {
+            FileResponse::CompressedChunk(c) => c.data.len(),
+            FileResponse::Chunk(c) => c.len(),
+            _ => 0,
+        };
+        let wait = upload.take(wire_size as u64);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
         }
-        context.update(&buffer[..count]);
-        let _ = tx
+        let sent = tx
             .send(Ok(GetFileResponse {
-                file_response: Some(FileResponse::Chunk(buffer)),
+                file_response: Some(file_response),
             }))
             .await;
-        start_bytes += count as u64;
+        // The peer went away, there is no point in reading any further.
+        if sent.is_err() {
+            return;
+        }
     }
-    let hash = HEXUPPER.encode(context.finish().as_ref());
+
+    let size = reader.size;
     let _ = tx
         .send(Ok(GetFileResponse {
             file_response: Some(FileResponse::Meta(FileMetaData {
-                file_size: size as u64,
-                path: path.to_string(),
-                hash: hash,
+                file_size: size,
+                path: name,
+                hash: reader.hash(),
             })),
         }))
         .await;
 }
+
+#[test]
+fn test_resolve_shared_path() {
+    let base = std::env::temp_dir().join(format!("landoh-resolve-{}", std::process::id()));
+    let share = base.join("share");
+    fs::create_dir_all(share.join("sub")).unwrap();
+    fs::write(share.join("sub").join("file"), b"shared").unwrap();
+    fs::write(base.join("secret"), b"not shared").unwrap();
+
+    let dirs = vec![Directory {
+        name: "share".to_string(),
+        paths: vec![share.to_string_lossy().to_string()],
+    }];
+    let file = share.join("sub").join("file").canonicalize().unwrap();
+
+    assert_eq!(resolve_shared_path(&dirs, "share/sub/file").unwrap(), file);
+    assert_eq!(resolve_shared_path(&dirs, "share\\sub\\file").unwrap(), file);
+    assert_eq!(resolve_shared_path(&dirs, "share/./sub//file").unwrap(), file);
+
+    for bad in [
+        "share/../secret",
+        "share/sub/../../secret",
+        "share\\..\\secret",
+        "share/sub/..\\..\\secret",
+        "/etc/passwd",
+        "\\etc\\passwd",
+        "C:\\Windows\\win.ini",
+        "other/sub/file",
+        "share/sub",
+        "share",
+        "",
+    ] {
+        assert!(resolve_shared_path(&dirs, bad).is_err(), "{:?} resolved", bad);
+    }
+
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(base.join("secret"), share.join("link")).unwrap();
+        assert!(resolve_shared_path(&dirs, "share/link").is_err());
+    }
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_resolve_shared_path_union() {
+    let base = std::env::temp_dir().join(format!("landoh-union-{}", std::process::id()));
+    let first = base.join("first");
+    let second = base.join("second");
+    fs::create_dir_all(&first).unwrap();
+    fs::create_dir_all(second.join("only")).unwrap();
+    fs::write(first.join("common"), b"first").unwrap();
+    fs::write(second.join("common"), b"second").unwrap();
+    fs::write(second.join("only").join("file"), b"second").unwrap();
+
+    let dirs = vec![Directory {
+        name: "games".to_string(),
+        paths: vec![
+            first.to_string_lossy().to_string(),
+            second.to_string_lossy().to_string(),
+        ],
+    }];
+
+    assert_eq!(
+        resolve_shared_path(&dirs, "games/common").unwrap(),
+        first.join("common").canonicalize().unwrap()
+    );
+    assert_eq!(
+        resolve_shared_path(&dirs, "games/only/file").unwrap(),
+        second.join("only").join("file").canonicalize().unwrap()
+    );
+    assert!(resolve_shared_path(&dirs, "games/missing").is_err());
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_list_share() {
+    let base = std::env::temp_dir().join(format!("landoh-list-{}", std::process::id()));
+    let share = base.join("share");
+    fs::create_dir_all(share.join("sub")).unwrap();
+    fs::write(share.join("sub").join("file"), b"shared").unwrap();
+    fs::write(base.join("secret"), b"not shared").unwrap();
+
+    let dir = Directory {
+        name: "share".to_string(),
+        paths: vec![share.to_string_lossy().to_string()],
+    };
+
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(base.join("secret"), share.join("out")).unwrap();
+        std::os::unix::fs::symlink(&base, share.join("up")).unwrap();
+        std::os::unix::fs::symlink(share.join("sub").join("file"), share.join("in")).unwrap();
+    }
+
+    let mut paths: Vec<String> = list_share(&dir, &Mutex::new(HashCache::default()))
+        .into_iter()
+        .map(|f| f.path.replace('\\', "/"))
+        .collect();
+    paths.sort();
+    #[cfg(unix)]
+    assert_eq!(paths, vec!["share/in", "share/sub/file"]);
+    #[cfg(not(unix))]
+    assert_eq!(paths, vec!["share/sub/file"]);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_received_path() {
+    let dest = Path::new("downloads");
+    assert_eq!(
+        received_path(dest, "report.pdf").unwrap(),
+        dest.join("report.pdf")
+    );
+    assert_eq!(
+        received_path(dest, "dir\\./sub//file").unwrap(),
+        dest.join("dir").join("sub").join("file")
+    );
+
+    for bad in [
+        "../outside",
+        "dir/../../outside",
+        "..\\outside",
+        "/etc/passwd",
+        "\\etc\\passwd",
+        "./",
+        "",
+    ] {
+        assert!(received_path(dest, bad).is_err(), "{:?} accepted", bad);
+    }
+}
+
+#[test]
+fn test_move_new() {
+    let dir = std::env::temp_dir().join(format!("landoh-move-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file");
+    let part = part_path(&path);
+
+    fs::write(&part, b"first").unwrap();
+    move_new(&part, &path).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"first");
+    assert!(!part.exists());
+
+    // A file that turned up meanwhile is kept.
+    fs::write(&part, b"second").unwrap();
+    let err = move_new(&part, &path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(fs::read(&path).unwrap(), b"first");
+
+    fs::remove_dir_all(&dir).unwrap();
+}
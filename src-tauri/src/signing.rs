@@ -0,0 +1,411 @@
+use std::{borrow::Cow, collections::HashMap, fmt, fs, path::PathBuf, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use data_encoding::BASE64;
+use log::info;
+use prost::Message;
+use ring::{
+    pkcs8::Document,
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::error::LandohError;
+use crate::group::GroupKey;
+use crate::pb;
+use crate::source::Source;
+use crate::tls::{write_private, KnownPeers};
+use crate::wire::{self, Datagram, Kind};
+
+/// Announcements whose timestamp is further than this from the local clock
+/// are rejected, which bounds how long a recorded one can be replayed.
+const MAX_CLOCK_SKEW: i64 = 5 * 60;
+
+/// The Ed25519 key a node signs its announcements with.
+pub struct SigningKey {
+    pair: Ed25519KeyPair,
+    public: String,
+}
+
+impl SigningKey {
+    pub fn generate() -> Result<Self, LandohError> {
+        Self::from_pkcs8(generate_pkcs8()?.as_ref())
+    }
+
+    /// Reads the key stored at `path`, generating and storing one on first
+    /// start.
+    pub fn load_or_generate(path: PathBuf) -> Result<Self, LandohError> {
+        if path.exists() {
+            return Self::from_pkcs8(&fs::read(&path)?)
+                .map_err(|err| LandohError::Config(format!("{:?}: {}", path, err)));
+        }
+
+        let pkcs8 = generate_pkcs8()?;
+        write_private(&path, pkcs8.as_ref())?;
+
+        let key = Self::from_pkcs8(pkcs8.as_ref())?;
+        info!("generated signing key {} in {:?}", key.public, path);
+        Ok(key)
+    }
+
+    fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, LandohError> {
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8)
+            .map_err(|err| LandohError::Config(format!("invalid signing key: {}", err)))?;
+        Ok(SigningKey {
+            public: BASE64.encode(pair.public_key().as_ref()),
+            pair,
+        })
+    }
+
+    pub fn public_key(&self) -> &str {
+        &self.public
+    }
+
+    pub fn sign(&self, source: &Source) -> Result<Announcement, LandohError> {
+        let source = wire::encode_source(source);
+        Ok(Announcement {
+            signature: self.pair.sign(&source).as_ref().to_vec(),
+            key: self.public.clone(),
+            source: Signed::Binary(source),
+        })
+    }
+}
+
+fn generate_pkcs8() -> Result<Document, LandohError> {
+    Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| LandohError::Internal("failed to generate signing key".to_string()))
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("public", &self.public)
+            .finish()
+    }
+}
+
+/// A `Source` as sent to the multicast group. The source is kept as the exact
+/// bytes that were signed, so verifying does not depend on encoding it again.
+#[derive(Debug, Clone)]
+pub struct Announcement {
+    source: Signed,
+    /// Base64 of the public key, as pinned in `KnownPeers`.
+    key: String,
+    signature: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+enum Signed {
+    Binary(Vec<u8>),
+    /// Signed by peers from before the binary encoding.
+    Json(String),
+}
+
+/// How peers from before the binary encoding announce themselves.
+#[derive(Deserialize, Serialize)]
+struct JsonAnnouncement {
+    source: String,
+    key: String,
+    signature: String,
+}
+
+fn invalid(msg: &str) -> LandohError {
+    LandohError::Discovery(format!("rejected announcement: {}", msg))
+}
+
+impl Announcement {
+    /// The announcement in its envelope, as sent to peers. Those signed as
+    /// JSON are sent the way older peers do.
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, LandohError> {
+        let source = match &self.source {
+            Signed::Binary(s) => s.clone(),
+            Signed::Json(s) => {
+                return serde_json::to_vec(&JsonAnnouncement {
+                    source: s.clone(),
+                    key: self.key.clone(),
+                    signature: BASE64.encode(&self.signature),
+                })
+                .map_err(|err| LandohError::Discovery(err.to_string()))
+            }
+        };
+        let key = BASE64
+            .decode(self.key.as_bytes())
+            .map_err(|_| invalid("malformed key"))?;
+        let announcement = pb::Announcement {
+            source: source.into(),
+            key: key.into(),
+            signature: self.signature.clone().into(),
+        };
+        Ok(wire::envelope(
+            Kind::Announcement,
+            &announcement.encode_to_vec(),
+        ))
+    }
+
+    /// Reads an announcement in its envelope, or the JSON of older peers.
+    pub(crate) fn decode(data: &[u8]) -> Result<Self, LandohError> {
+        match wire::parse(data)? {
+            Datagram::Announcement(body) => {
+                let a = pb::Announcement::decode(body).map_err(|err| invalid(&err.to_string()))?;
+                Ok(Announcement {
+                    source: Signed::Binary(a.source.to_vec()),
+                    key: BASE64.encode(&a.key),
+                    signature: a.signature.to_vec(),
+                })
+            }
+            Datagram::Legacy(json) => {
+                let a = serde_json::from_slice::<JsonAnnouncement>(json).map_err(|err| {
+                    LandohError::Discovery(format!("rejected unsigned announcement: {}", err))
+                })?;
+                Ok(Announcement {
+                    signature: BASE64
+                        .decode(a.signature.as_bytes())
+                        .map_err(|_| invalid("malformed signature"))?,
+                    source: Signed::Json(a.source),
+                    key: a.key,
+                })
+            }
+            _ => Err(invalid("not an announcement")),
+        }
+    }
+
+    fn verify(&self) -> Result<Source, LandohError> {
+        let key = BASE64
+            .decode(self.key.as_bytes())
+            .map_err(|_| invalid("malformed key"))?;
+        let signed = match &self.source {
+            Signed::Binary(s) => s.as_slice(),
+            Signed::Json(s) => s.as_bytes(),
+        };
+        UnparsedPublicKey::new(&ED25519, key)
+            .verify(signed, &self.signature)
+            .map_err(|_| invalid("bad signature"))?;
+
+        match &self.source {
+            Signed::Binary(s) => wire::decode_source(s),
+            Signed::Json(s) => {
+                serde_json::from_str::<Source>(s).map_err(|err| invalid(&err.to_string()))
+            }
+        }
+    }
+}
+
+/// Checks announcements received from the group. Besides the signature, the
+/// key has to be the one pinned for the peer's id and every announcement has
+/// to be newer than the last one accepted from that peer. Copies of that one,
+/// heard on another address or interface, are dropped quietly. In a group,
+/// only announcements encrypted with its key are read at all.
+#[derive(Debug)]
+pub(crate) struct Verifier {
+    id: String,
+    known_peers: Arc<KnownPeers>,
+    group: Option<Arc<GroupKey>>,
+    /// Timestamp and key of the last announcement accepted per peer.
+    last: HashMap<String, (DateTime<Utc>, String)>,
+}
+
+impl Verifier {
+    /// `id` is this node's own id, whose announcements are ignored.
+    pub(crate) fn new(
+        id: String,
+        known_peers: Arc<KnownPeers>,
+        group: Option<Arc<GroupKey>>,
+    ) -> Self {
+        Verifier {
+            id,
+            known_peers,
+            group,
+            last: HashMap::new(),
+        }
+    }
+
+    /// Returns the announced source, or `None` for this node's own
+    /// announcements and copies of one already accepted.
+    pub(crate) fn accept(&mut self, data: &[u8]) -> Result<Option<Source>, LandohError> {
+        self.accept_at(data, Utc::now())
+    }
+
+    fn accept_at(
+        &mut self,
+        data: &[u8],
+        now: DateTime<Utc>,
+    ) -> Result<Option<Source>, LandohError> {
+        let data = match (&self.group, wire::parse(data)?) {
+            // Members from before the envelope encrypt without one.
+            (Some(g), Datagram::Sealed(sealed)) | (Some(g), Datagram::Legacy(sealed)) => {
+                Cow::Owned(g.open(sealed)?)
+            }
+            (Some(_), _) => return Err(invalid("not encrypted for the group")),
+            (None, Datagram::Sealed(_)) => return Err(invalid("encrypted for a group")),
+            (None, _) => Cow::Borrowed(data),
+        };
+        let announcement = Announcement::decode(&data)?;
+        let source = announcement.verify()?;
+        if source.id == self.id {
+            return Ok(None);
+        }
+
+        if (now - source.timestamp).num_seconds().abs() > MAX_CLOCK_SKEW {
+            return Err(LandohError::Discovery(format!(
+                "rejected announcement of {} from {}",
+                source.id, source.timestamp
+            )));
+        }
+        if let Some((last, key)) = self.last.get(&source.id) {
+            if source.timestamp == *last && announcement.key == *key {
+                return Ok(None);
+            }
+            if source.timestamp <= *last {
+                return Err(LandohError::Discovery(format!(
+                    "rejected replayed announcement of {}",
+                    source.id
+                )));
+            }
+        }
+        self.known_peers
+            .check_announcement(&source.id, &announcement.key)?;
+        if let Some(fp) = &source.fingerprint {
+            self.known_peers.remember_fingerprint(&source.id, fp);
+        }
+
+        self.last.insert(
+            source.id.clone(),
+            (source.timestamp, announcement.key.clone()),
+        );
+        Ok(Some(source))
+    }
+}
+
+#[test]
+fn test_announcement_verification() {
+    let key = SigningKey::generate().unwrap();
+    let mut verifier = Verifier::new("self".to_string(), Arc::new(KnownPeers::default()), None);
+    let encode = |a: &Announcement| a.encode().unwrap();
+
+    let source = Source::new("peer".to_string(), "nick".to_string(), None, vec![]);
+    let signed = encode(&key.sign(&source).unwrap());
+    let now = source.timestamp;
+    assert_eq!(
+        verifier.accept_at(&signed, now).unwrap().unwrap().id,
+        "peer"
+    );
+
+    // Copies heard on another address are dropped, older replays, unsigned
+    // and tampered announcements are rejected.
+    assert!(verifier.accept_at(&signed, now).unwrap().is_none());
+    let mut later = source.clone();
+    later.timestamp = now + chrono::Duration::seconds(5);
+    assert!(verifier
+        .accept_at(&serde_json::to_vec(&later).unwrap(), now)
+        .is_err());
+    let mut tampered = key.sign(&later).unwrap();
+    if let Signed::Binary(s) = &mut tampered.source {
+        let at = s.windows(4).position(|w| w == b"nick").unwrap();
+        s[at..at + 4].copy_from_slice(b"evil");
+    }
+    assert!(verifier.accept_at(&encode(&tampered), now).is_err());
+
+    // Someone else's key for a known id.
+    let other = SigningKey::generate().unwrap();
+    assert!(matches!(
+        verifier.accept_at(&encode(&other.sign(&later).unwrap()), now),
+        Err(LandohError::KeyChanged { .. })
+    ));
+
+    let stale = encode(&key.sign(&later).unwrap());
+    assert!(verifier
+        .accept_at(&stale, now + chrono::Duration::seconds(MAX_CLOCK_SKEW + 10))
+        .is_err());
+    assert!(verifier.accept_at(&stale, now).unwrap().is_some());
+    assert!(verifier.accept_at(&signed, now).is_err());
+
+    let own = Source::new("self".to_string(), "me".to_string(), None, vec![]);
+    assert!(verifier
+        .accept_at(&encode(&key.sign(&own).unwrap()), now)
+        .unwrap()
+        .is_none());
+
+    // Members of a group only read announcements encrypted for it.
+    let group = Arc::new(GroupKey::new("team"));
+    let mut member = Verifier::new(
+        "self".to_string(),
+        Arc::new(KnownPeers::default()),
+        Some(Arc::clone(&group)),
+    );
+    assert!(member.accept_at(&signed, now).is_err());
+    let sealed = wire::envelope(Kind::Sealed, &group.seal(&signed).unwrap());
+    assert!(verifier.accept_at(&sealed, now).is_err());
+    assert!(member.accept_at(&sealed, now).unwrap().is_some());
+}
+
+#[test]
+fn test_json_announcements() {
+    // As signed and sent by peers from before the binary encoding.
+    let key = SigningKey::generate().unwrap();
+    let json = |source: &Source| {
+        let source = serde_json::to_string(source).unwrap();
+        Announcement {
+            signature: key.pair.sign(source.as_bytes()).as_ref().to_vec(),
+            key: key.public.clone(),
+            source: Signed::Json(source),
+        }
+        .encode()
+        .unwrap()
+    };
+    let source = Source::new(
+        "peer".to_string(),
+        "nick".to_string(),
+        None,
+        vec!["share".to_string()],
+    );
+    let now = source.timestamp;
+    let old = json(&source);
+    assert!(old.starts_with(b"{"));
+
+    let mut verifier = Verifier::new("self".to_string(), Arc::new(KnownPeers::default()), None);
+    let accepted = verifier.accept_at(&old, now).unwrap().unwrap();
+    assert_eq!(accepted.shared_directories, vec!["share"]);
+    // The same key signs the binary ones once the peer is updated.
+    let mut later = source.clone();
+    later.timestamp = now + chrono::Duration::seconds(5);
+    assert!(verifier
+        .accept_at(&key.sign(&later).unwrap().encode().unwrap(), now)
+        .unwrap()
+        .is_some());
+
+    // Members from before the envelope seal the JSON as it is.
+    let group = Arc::new(GroupKey::new("team"));
+    let mut member = Verifier::new(
+        "self".to_string(),
+        Arc::new(KnownPeers::default()),
+        Some(Arc::clone(&group)),
+    );
+    assert!(member
+        .accept_at(&group.seal(&old).unwrap(), now)
+        .unwrap()
+        .is_some());
+}
+
+#[test]
+fn test_large_announcements() {
+    let key = SigningKey::generate().unwrap();
+    let shares = (0..500).map(|i| format!("share number {}", i)).collect();
+    let source = Source::new("peer".to_string(), "nick".to_string(), None, shares);
+    let fragments = wire::fragment(key.sign(&source).unwrap().encode().unwrap(), 7).unwrap();
+    assert!(fragments.len() > 1);
+
+    let mut reassembler = wire::Reassembler::default();
+    let from = "192.168.1.5:40000".parse().unwrap();
+    let datagram = fragments
+        .iter()
+        .filter_map(|f| reassembler.push(from, f).unwrap())
+        .next()
+        .unwrap();
+    let mut verifier = Verifier::new("self".to_string(), Arc::new(KnownPeers::default()), None);
+    let accepted = verifier
+        .accept_at(&datagram, source.timestamp)
+        .unwrap()
+        .unwrap();
+    assert_eq!(accepted.shared_directories.len(), 500);
+}
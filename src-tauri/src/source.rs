@@ -1,4 +1,16 @@
-use chrono::{DateTime, Utc};
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+
+use chrono::{DateTime, Duration, Utc};
+
+/// Port peers serve on unless they announce another one.
+pub const DEFAULT_PORT: u16 = 9001;
+/// Version of the gRPC protocol this node speaks, announced with its `Source`.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Seconds between the announcements of a node unless configured otherwise.
+pub const ANNOUNCE_INTERVAL: u64 = 5;
+/// Seconds peers are kept after their last announcement unless configured
+/// otherwise.
+pub const PEER_TTL: u64 = 30;
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Source {
@@ -6,7 +18,27 @@ pub struct Source {
     pub nickname: String,
     pub shared_directories: Vec<String>,
     pub ip: Option<String>,
-    pub timestamp: DateTime<Utc>
+    pub timestamp: DateTime<Utc>,
+    /// Fingerprint of the key the peer serves with.
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /// Port the peer serves on, `DEFAULT_PORT` if it did not announce one.
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Version of the protocol the peer speaks, not set by peers from before
+    /// it was announced.
+    #[serde(default)]
+    pub protocol: Option<u32>,
+    /// Interface the announcement came in on, set by the receiver.
+    #[serde(default)]
+    pub interface: Option<String>,
+    /// Set on the last announcement of a peer that stops sharing, receivers
+    /// drop it right away instead of waiting for it to expire.
+    #[serde(default)]
+    pub leaving: bool,
+    /// When an announcement last came in from `ip`, kept by the receiver.
+    #[serde(skip)]
+    pub address_seen: Option<DateTime<Utc>>,
 }
 
 impl Source {
@@ -21,25 +53,191 @@ impl Source {
             nickname,
             ip,
             shared_directories,
-            timestamp: Utc::now()
+            timestamp: Utc::now(),
+            fingerprint: None,
+            port: None,
+            protocol: Some(PROTOCOL_VERSION),
+            interface: None,
+            leaving: false,
+            address_seen: None,
         }
     }
 
-    pub fn update(
+    pub fn with_fingerprint(mut self, fingerprint: String) -> Self {
+        self.fingerprint = Some(fingerprint);
+        self
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// Turns the announcement into the goodbye of the peer.
+    pub fn leaving(mut self) -> Self {
+        self.leaving = true;
+        self
+    }
+
+    /// Takes over everything `announced`, received from `ip`. The address in
+    /// use is only given up once nothing came in from it for `ttl`, the time
+    /// peers are kept for.
+    pub fn update(&mut self, announced: Source, ip: Option<String>, ttl: Duration) {
+        self.update_at(announced, ip, Utc::now(), ttl)
+    }
+
+    fn update_at(
         &mut self,
-        nickname: String,
+        announced: Source,
         ip: Option<String>,
-        shared_directories: Vec<String>,
+        now: DateTime<Utc>,
+        ttl: Duration,
     ) {
-            self.nickname = nickname;
-            self.ip = ip;
-            self.shared_directories = shared_directories;
-            self.timestamp = Utc::now();
+        // Peers are heard on every address and interface they share with
+        // this node. The one in use is kept while it still delivers their
+        // announcements, so it does not flip between them.
+        let alive = matches!(self.address_seen, Some(seen) if now - seen <= ttl);
+        let current = ip == self.ip || self.ip.is_none() || !alive;
+        let (ip, interface) = if current {
+            self.address_seen = Some(now);
+            (ip, announced.interface.clone())
+        } else {
+            (self.ip.take(), self.interface.take())
+        };
+        *self = Source {
+            ip,
+            interface,
+            address_seen: self.address_seen,
+            timestamp: now,
+            ..announced
+        };
+    }
+
+    /// Whether the peer speaks the protocol of this node. Peers from before
+    /// the version was announced speak the first one.
+    pub fn is_compatible(&self) -> bool {
+        self.protocol.unwrap_or(1) == PROTOCOL_VERSION
+    }
+
+    /// Where the peer's server is reached, on the port it announced.
+    pub fn url(&self) -> Option<String> {
+        let port = self.port.unwrap_or(DEFAULT_PORT);
+        // Link-local IPv6 addresses carry the zone they were seen in.
+        let addr = match self.ip.as_ref()?.split_once('%') {
+            Some((ip, zone)) => SocketAddr::V6(SocketAddrV6::new(
+                ip.parse().ok()?,
+                port,
+                0,
+                zone.parse().ok()?,
+            )),
+            None => SocketAddr::new(self.ip.as_ref()?.parse().ok()?, port),
+        };
+        Some(format!("https://{}", addr))
+    }
+
+    /// This node's address on the interface the peer was seen on, to reach
+    /// it the same way. IPv6 peers carry their zone instead.
+    pub fn local_address(&self) -> Option<IpAddr> {
+        let ip: IpAddr = self.ip.as_ref()?.parse().ok()?;
+        if !ip.is_ipv4() {
+            return None;
         }
+        crate::interface::ipv4_of(self.interface.as_ref()?).map(IpAddr::V4)
+    }
 
-    pub fn is_outdated(&self) -> bool {
-        let diff = Utc::now().time() - self.timestamp.time();
-        diff.num_seconds() > 30
+    /// Whether the last announcement is older than `ttl`.
+    pub fn is_outdated(&self, ttl: Duration) -> bool {
+        self.is_outdated_at(Utc::now(), ttl)
     }
+
+    fn is_outdated_at(&self, now: DateTime<Utc>, ttl: Duration) -> bool {
+        now - self.timestamp > ttl
     }
+}
+
+#[test]
+fn test_source_url() {
+    let mut s = Source::new("id".to_string(), "nick".to_string(), None, vec![]);
+    assert_eq!(s.url(), None);
 
+    s.ip = Some("192.168.1.5".to_string());
+    assert_eq!(s.url().unwrap(), "https://192.168.1.5:9001");
+    s = s.with_port(9100);
+    assert_eq!(s.url().unwrap(), "https://192.168.1.5:9100");
+    s.ip = Some("fd00::1".to_string());
+    assert_eq!(s.url().unwrap(), "https://[fd00::1]:9100");
+    s.ip = Some("fe80::1%3".to_string());
+    assert_eq!(s.url().unwrap(), "https://[fe80::1%3]:9100");
+
+    // Announcements of peers that do not send a port or version yet.
+    let old: Source = serde_json::from_str(
+        r#"{"id": "a", "nickname": "n", "shared_directories": [], "ip": null,
+            "timestamp": "2024-03-01T12:00:00Z"}"#,
+    )
+    .unwrap();
+    assert_eq!((old.port, old.protocol), (None, None));
+
+    let mut known = old.clone();
+    known.update(
+        s,
+        Some("10.0.0.2".to_string()),
+        Duration::seconds(PEER_TTL as i64),
+    );
+    assert_eq!(known.url().unwrap(), "https://10.0.0.2:9100");
+    assert_eq!(known.protocol, Some(PROTOCOL_VERSION));
+
+    assert!(old.is_compatible() && known.is_compatible());
+    known.protocol = Some(PROTOCOL_VERSION + 1);
+    assert!(!known.is_compatible());
+}
+
+#[test]
+fn test_source_address() {
+    let ttl = Duration::seconds(PEER_TTL as i64);
+    let start: DateTime<Utc> = "2024-03-01T12:00:00Z".parse().unwrap();
+    let announced = Source::new("id".to_string(), "nick".to_string(), None, vec![]);
+    let heard = |s: &mut Source, ip: &str, after: i64, ttl: Duration| {
+        let at = start + Duration::seconds(after);
+        s.update_at(announced.clone(), Some(ip.to_string()), at, ttl);
+        s.ip.clone().unwrap()
+    };
+    let mut s = announced.clone();
+    assert_eq!(heard(&mut s, "10.0.0.2", 0, ttl), "10.0.0.2");
+
+    // Copies heard on other addresses do not take over.
+    assert_eq!(heard(&mut s, "fd00::2", 5, ttl), "10.0.0.2");
+    heard(&mut s, "10.0.0.2", 10, ttl);
+    assert_eq!(heard(&mut s, "fd00::2", 40, ttl), "10.0.0.2");
+    assert_eq!(s.timestamp, start + Duration::seconds(40));
+
+    // Unless nothing came in from the one in use for as long as peers are kept.
+    let longer = Duration::seconds(60);
+    assert_eq!(heard(&mut s, "10.0.0.2", 60, longer), "10.0.0.2");
+    assert_eq!(heard(&mut s, "fd00::2", 110, longer), "10.0.0.2");
+    assert_eq!(heard(&mut s, "fd00::2", 110, ttl), "fd00::2");
+}
+
+#[test]
+fn test_source_expiry() {
+    let ttl = Duration::seconds(PEER_TTL as i64);
+    let mut s = Source::new("id".to_string(), "nick".to_string(), None, vec![]);
+    s.timestamp = "2024-03-01T12:00:00Z".parse().unwrap();
+    let at = |t: &str| t.parse::<DateTime<Utc>>().unwrap();
+
+    assert!(!s.is_outdated_at(at("2024-03-01T12:00:30Z"), ttl));
+    assert!(s.is_outdated_at(at("2024-03-01T12:00:31Z"), ttl));
+    assert!(!s.is_outdated_at(at("2024-03-01T12:00:31Z"), Duration::seconds(60)));
+    // Announced just before midnight, still fresh just after it.
+    s.timestamp = at("2024-03-01T23:59:50Z");
+    assert!(!s.is_outdated_at(at("2024-03-02T00:00:10Z"), ttl));
+    assert!(s.is_outdated_at(at("2024-03-02T00:00:30Z"), ttl));
+    // Not seen for a day but at the same time of day.
+    assert!(s.is_outdated_at(at("2024-03-02T23:59:50Z"), ttl));
+    // Clocks slightly ahead of this one.
+    assert!(!s.is_outdated_at(at("2024-03-01T23:59:45Z"), ttl));
+    assert!(s.is_outdated(ttl));
+
+    assert!(Source::new("id".to_string(), "nick".to_string(), None, vec![])
+        .leaving()
+        .leaving);
+}
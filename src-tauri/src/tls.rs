@@ -0,0 +1,614 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use data_encoding::HEXUPPER;
+use log::{info, warn};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::{
+    self,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::danger::{ClientCertVerified, ClientCertVerifier},
+    ClientConfig, DigitallySignedStruct, DistinguishedName, ServerConfig, SignatureScheme,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor, TlsConnector};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tonic::Status;
+
+use crate::error::LandohError;
+
+/// Name every node's certificate is issued for. Peers are told apart by
+/// fingerprint, not by name.
+const SERVER_NAME: &str = "landoh";
+/// Connections that have not finished the handshake by then are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// SHA-256 of a DER encoded certificate.
+pub fn fingerprint(der: &[u8]) -> String {
+    HEXUPPER.encode(digest(&SHA256, der).as_ref())
+}
+
+/// The self-signed certificate and key a node serves gRPC with.
+#[derive(Clone)]
+pub struct Identity {
+    /// Certificate and private key, PEM encoded.
+    pem: String,
+    fingerprint: String,
+}
+
+impl Identity {
+    pub fn generate() -> Result<Self, LandohError> {
+        let key = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])
+            .map_err(|err| LandohError::Internal(format!("failed to generate key: {}", err)))?;
+
+        Ok(Identity {
+            pem: format!("{}{}", key.cert.pem(), key.key_pair.serialize_pem()),
+            fingerprint: fingerprint(key.cert.der()),
+        })
+    }
+
+    /// Reads the identity stored at `path`, generating and storing one on
+    /// first start.
+    pub fn load_or_generate(path: PathBuf) -> Result<Self, LandohError> {
+        if path.exists() {
+            let pem = fs::read_to_string(&path)?;
+            return Self::from_pem(pem)
+                .map_err(|err| LandohError::Config(format!("{:?}: {}", path, err)));
+        }
+
+        let identity = Self::generate()?;
+        write_private(&path, identity.pem.as_bytes())?;
+
+        info!("generated key {} in {:?}", identity.fingerprint, path);
+        Ok(identity)
+    }
+
+    fn from_pem(pem: String) -> io::Result<Self> {
+        let (cert, _) = parse_pem(&pem)?;
+        Ok(Identity {
+            fingerprint: fingerprint(&cert),
+            pem,
+        })
+    }
+
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Clients may present their node certificate, which lets shares be
+    /// restricted to them. Those that do not are still served.
+    fn server_config(&self) -> Result<ServerConfig, LandohError> {
+        let (cert, key) = parse_pem(&self.pem)?;
+        let mut config = ServerConfig::builder()
+            .with_client_cert_verifier(Arc::new(AnyCertificate(algorithms())))
+            .with_single_cert(vec![cert], key)
+            .map_err(|err| LandohError::Config(format!("invalid key: {}", err)))?;
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        Ok(config)
+    }
+}
+
+/// The certificate and private key of a PEM file. Each parser skips what it
+/// is not looking for.
+fn parse_pem(pem: &str) -> io::Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let cert = match rustls_pemfile::certs(&mut pem.as_bytes()).next() {
+        Some(c) => c?,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "no certificate")),
+    };
+    match rustls_pemfile::private_key(&mut pem.as_bytes())? {
+        Some(key) => Ok((cert, key)),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "no private key")),
+    }
+}
+
+/// Creates a file only the current user may read. Fails if it exists.
+pub(crate) fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(data)
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("fingerprint", &self.fingerprint)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize, Default, Debug)]
+struct Pins {
+    /// Fingerprints by peer id, or by address for peers of unknown id.
+    peers: HashMap<String, String>,
+    /// Keys peers sign their announcements with, by peer id.
+    #[serde(default)]
+    announcements: HashMap<String, String>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    pins: Pins,
+    /// Ids and fingerprints peers announced, by address.
+    announced: HashMap<String, (String, Option<String>)>,
+    /// Fingerprints from announcements with a verified signature, by id.
+    verified: HashMap<String, String>,
+    /// Local addresses connections are made from, by peer address.
+    routes: HashMap<String, IpAddr>,
+}
+
+/// Fingerprints of the peers this node has connected to and keys of the peers
+/// it received announcements from. The first key a peer presents is trusted
+/// and pinned, and later connections or announcements fail if it changes.
+#[derive(Debug, Default)]
+pub struct KnownPeers {
+    path: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+impl KnownPeers {
+    pub fn load(path: PathBuf) -> Self {
+        let pins = match fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str::<Pins>(&s).unwrap_or_else(|err| {
+                warn!("discarding unreadable known peers {:?}: {}", &path, err);
+                Pins::default()
+            }),
+            Err(_) => Pins::default(),
+        };
+
+        KnownPeers {
+            path: Some(path),
+            state: Mutex::new(State {
+                pins,
+                ..State::default()
+            }),
+        }
+    }
+
+    /// Records what a peer announced about itself, so that connections to
+    /// `addr` are checked against the key pinned for `id`. The announced
+    /// fingerprint is expected from peers that have not been pinned yet.
+    pub fn announce(&self, addr: &str, id: &str, fingerprint: Option<String>) {
+        let addr = match authority(addr) {
+            Ok(a) => a,
+            Err(_) => return,
+        };
+        self.lock()
+            .announced
+            .insert(addr, (id.to_string(), fingerprint));
+    }
+
+    /// Makes connections to `addr` from `local`, so they leave through the
+    /// interface the peer was seen on even if the routing table prefers
+    /// another one.
+    pub fn route(&self, addr: &str, local: IpAddr) {
+        if let Ok(a) = authority(addr) {
+            self.lock().routes.insert(a, local);
+        }
+    }
+
+    fn route_for(&self, authority: &str) -> Option<IpAddr> {
+        self.lock().routes.get(authority).copied()
+    }
+
+    /// Drops the keys pinned for `peer`, so that the next ones it presents
+    /// are trusted. Used after a peer was reinstalled.
+    pub fn forget(&self, peer: &str) -> Result<(), LandohError> {
+        let mut s = self.lock();
+        let fingerprint = s.pins.peers.remove(peer);
+        let key = s.pins.announcements.remove(peer);
+        if fingerprint.is_some() || key.is_some() {
+            self.save(&s.pins)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the key an announcement of `id` was signed with, pinning it if
+    /// the peer is new.
+    pub(crate) fn check_announcement(&self, id: &str, key: &str) -> Result<(), LandohError> {
+        let mut s = self.lock();
+        match s.pins.announcements.get(id) {
+            Some(expected) if expected == key => return Ok(()),
+            Some(expected) => {
+                return Err(LandohError::KeyChanged {
+                    peer: id.to_string(),
+                    expected: expected.clone(),
+                    actual: key.to_string(),
+                })
+            }
+            None => info!("trusting announcement key {} of peer {}", key, id),
+        }
+
+        s.pins.announcements.insert(id.to_string(), key.to_string());
+        if let Err(err) = self.save(&s.pins) {
+            warn!("known peers will not be persisted: {}", err);
+        }
+        Ok(())
+    }
+
+    /// Records the fingerprint a verified announcement of `id` carried.
+    pub(crate) fn remember_fingerprint(&self, id: &str, fingerprint: &str) {
+        self.lock()
+            .verified
+            .insert(id.to_string(), fingerprint.to_string());
+    }
+
+    /// Whether a certificate with `fingerprint` belongs to the peer `id`,
+    /// going by the key pinned when connecting to it or else the one it
+    /// announced.
+    pub(crate) fn has_fingerprint(&self, id: &str, fingerprint: &str) -> bool {
+        let s = self.lock();
+        s.pins
+            .peers
+            .get(id)
+            .or_else(|| s.verified.get(id))
+            .map(|fp| fp == fingerprint)
+            .unwrap_or(false)
+    }
+
+    /// The id of the peer with the certificate `fingerprint`, if it is known.
+    pub(crate) fn peer_id(&self, fingerprint: &str) -> Option<String> {
+        let s = self.lock();
+        s.pins
+            .peers
+            .iter()
+            .chain(s.verified.iter())
+            .find(|(_, fp)| *fp == fingerprint)
+            .map(|(id, _)| id.clone())
+    }
+
+    /// Checks the key presented by the peer at `addr`, pinning it if the
+    /// peer is new.
+    fn check(&self, addr: &str, presented: &str) -> Result<(), LandohError> {
+        let mut s = self.lock();
+        let (peer, announced) = match s.announced.get(addr) {
+            Some((id, fp)) => (id.clone(), fp.clone()),
+            None => (addr.to_string(), None),
+        };
+
+        match s.pins.peers.get(&peer).cloned().or(announced) {
+            Some(expected) if expected == presented => {}
+            Some(expected) => {
+                return Err(LandohError::KeyChanged {
+                    peer: if peer == addr {
+                        peer
+                    } else {
+                        format!("{} at {}", peer, addr)
+                    },
+                    expected,
+                    actual: presented.to_string(),
+                })
+            }
+            None => info!("trusting key {} of peer {}", presented, peer),
+        }
+
+        if s.pins.peers.get(&peer).map(String::as_str) != Some(presented) {
+            s.pins.peers.insert(peer, presented.to_string());
+            if let Err(err) = self.save(&s.pins) {
+                warn!("known peers will not be persisted: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&self, pins: &Pins) -> Result<(), LandohError> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let payload = serde_json::to_string_pretty(pins).map_err(io::Error::from)?;
+
+        // Moved into place once complete, so a crash while writing cannot
+        // leave a truncated file behind that forgets every pinned key.
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        fs::write(&tmp, payload)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The `host:port` part of a peer address.
+fn authority(addr: &str) -> Result<String, LandohError> {
+    let invalid = |msg: String| {
+        LandohError::InvalidRequest(format!("invalid peer address {}: {}", addr, msg))
+    };
+
+    // The zone of a scoped IPv6 address is no valid part of a URI, so it is
+    // taken off for parsing and put back afterwards.
+    let (unscoped, zone) = split_zone(addr).map_err(invalid)?;
+    let uri: Uri = unscoped
+        .parse()
+        .map_err(|err| invalid(format!("{}", err)))?;
+    match (uri.host(), uri.port_u16(), zone) {
+        (Some(host), Some(port), None) => Ok(format!("{}:{}", host, port)),
+        (Some(host), Some(port), Some(zone)) => {
+            let ip: Ipv6Addr = host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse()
+                .map_err(|err| invalid(format!("{}", err)))?;
+            Ok(SocketAddrV6::new(ip, port, 0, zone).to_string())
+        }
+        _ => Err(invalid("host and port are required".to_string())),
+    }
+}
+
+/// Splits the numeric zone off a scoped IPv6 address like `[fe80::1%3]:9001`.
+fn split_zone(addr: &str) -> Result<(String, Option<u32>), String> {
+    let (start, end) = match (addr.find('%'), addr.find(']')) {
+        (Some(s), Some(e)) if s < e => (s, e),
+        _ => return Ok((addr.to_string(), None)),
+    };
+    let zone = addr[start + 1..end]
+        .parse::<u32>()
+        .map_err(|_| format!("invalid zone: {}", &addr[start + 1..end]))?;
+    Ok((format!("{}{}", &addr[..start], &addr[end..]), Some(zone)))
+}
+
+fn algorithms() -> WebPkiSupportedAlgorithms {
+    rustls::crypto::ring::default_provider().signature_verification_algorithms
+}
+
+/// Opens a channel to the peer at `addr`. Peers are always reached over TLS,
+/// whatever scheme `addr` names, and their key is checked against
+/// `known_peers`. With an `identity`, the peer can tell who is connecting.
+pub(crate) async fn connect(
+    known_peers: &Arc<KnownPeers>,
+    identity: Option<&Identity>,
+    addr: &str,
+) -> Result<Channel, LandohError> {
+    let authority = authority(addr)?;
+    // TLS is done by the connector, the endpoint must not try it again. It
+    // only names the peer, the connector dials `authority` with its zone.
+    let (unscoped, _) = split_zone(&authority).map_err(LandohError::InvalidRequest)?;
+    let endpoint = Endpoint::from_shared(format!("http://{}", unscoped))
+        .map_err(|err| LandohError::InvalidRequest(err.to_string()))?;
+    let builder = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(algorithms())));
+    let mut config = match identity {
+        Some(i) => {
+            let (cert, key) = parse_pem(&i.pem)?;
+            builder
+                .with_client_auth_cert(vec![cert], key)
+                .map_err(|err| LandohError::Config(format!("invalid key: {}", err)))?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    let connector = TlsConnector::from(Arc::new(config));
+
+    let peers = Arc::clone(known_peers);
+    let connect = tower::service_fn(move |_: Uri| {
+        let (connector, peers, authority) =
+            (connector.clone(), Arc::clone(&peers), authority.clone());
+        async move {
+            let unavailable = |err: io::Error| {
+                LandohError::Transport(Box::new(Status::unavailable(err.to_string())))
+            };
+            let tcp = match (peers.route_for(&authority), authority.parse::<SocketAddr>()) {
+                (Some(local), Ok(remote)) if local.is_ipv4() == remote.is_ipv4() => {
+                    let socket = match local {
+                        IpAddr::V4(_) => TcpSocket::new_v4(),
+                        IpAddr::V6(_) => TcpSocket::new_v6(),
+                    }
+                    .map_err(unavailable)?;
+                    socket
+                        .bind(SocketAddr::new(local, 0))
+                        .map_err(unavailable)?;
+                    socket.connect(remote).await.map_err(unavailable)?
+                }
+                _ => TcpStream::connect(&authority).await.map_err(unavailable)?,
+            };
+            tcp.set_nodelay(true).map_err(unavailable)?;
+            let name = ServerName::try_from(SERVER_NAME)
+                .map_err(|err| LandohError::Internal(err.to_string()))?;
+            let tls = connector.connect(name, tcp).await.map_err(unavailable)?;
+
+            let presented = match tls.get_ref().1.peer_certificates() {
+                Some([cert, ..]) => fingerprint(cert),
+                _ => return Err(LandohError::Internal("peer sent no certificate".into())),
+            };
+            peers.check(&authority, &presented)?;
+            Ok::<_, LandohError>(tls)
+        }
+    });
+    Ok(endpoint.connect_with_connector(connect).await?)
+}
+
+/// Accepts TLS connections on `listener` as `identity`. Handshakes run
+/// concurrently, and connections that fail them are dropped.
+pub(crate) fn incoming(
+    listener: TcpListener,
+    identity: &Identity,
+) -> Result<ReceiverStream<io::Result<TlsStream<TcpStream>>>, LandohError> {
+    let acceptor = TlsAcceptor::from(Arc::new(identity.server_config()?));
+    let (tx, rx) = mpsc::channel(32);
+
+    tokio::spawn(async move {
+        loop {
+            let (tcp, addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(a) => a,
+                    Err(err) => {
+                        warn!("failed to accept connection: {}", err);
+                        continue;
+                    }
+                },
+                _ = tx.closed() => return,
+            };
+            if let Err(err) = tcp.set_nodelay(true) {
+                warn!("failed to set TCP_NODELAY for {}: {}", addr, err);
+            }
+
+            let (acceptor, tx) = (acceptor.clone(), tx.clone());
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                    Ok(Ok(tls)) => {
+                        let _ = tx.send(Ok(tls)).await;
+                    }
+                    Ok(Err(err)) => warn!("TLS handshake with {} failed: {}", addr, err),
+                    Err(_) => warn!("TLS handshake with {} timed out", addr),
+                }
+            });
+        }
+    });
+    Ok(ReceiverStream::new(rx))
+}
+
+/// Accepts any certificate. Nodes sign their own, so there is no authority to
+/// check them against. Instead `connect` and the access rules of the server
+/// compare the fingerprint once the handshake proved that the peer holds the
+/// certificate's key.
+#[derive(Debug)]
+struct AnyCertificate(WebPkiSupportedAlgorithms);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for AnyCertificate {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
+
+#[test]
+fn test_known_peers_pinning() {
+    let peers = KnownPeers::default();
+    let addr = "192.168.0.2:9001";
+
+    // Unknown peers are trusted on first use and pinned by address.
+    peers.check(addr, "A").unwrap();
+    peers.check(addr, "A").unwrap();
+    assert!(matches!(
+        peers.check(addr, "B"),
+        Err(LandohError::KeyChanged { .. })
+    ));
+
+    // Announced peers are pinned by id, whatever address they use.
+    peers.announce("https://192.168.0.3:9001", "peer", Some("C".to_string()));
+    assert!(peers.check("192.168.0.3:9001", "D").is_err());
+    peers.check("192.168.0.3:9001", "C").unwrap();
+    peers.announce("https://192.168.0.4:9001", "peer", Some("E".to_string()));
+    assert!(peers.check("192.168.0.4:9001", "E").is_err());
+
+    peers.forget("peer").unwrap();
+    peers.check("192.168.0.4:9001", "E").unwrap();
+
+    assert_eq!(
+        authority("https://[fe80::1%3]:9001").unwrap(),
+        "[fe80::1%3]:9001"
+    );
+    assert_eq!(
+        authority("https://[fd00::1]:9001").unwrap(),
+        "[fd00::1]:9001"
+    );
+    assert!(authority("https://[fe80::1%eth0]:9001").is_err());
+    peers.announce("https://[fe80::2%3]:9001", "scoped", Some("F".to_string()));
+    assert!(peers.check("[fe80::2%3]:9001", "G").is_err());
+
+    // Pins survive a restart.
+    let path = std::env::temp_dir().join(format!("landoh-peers-{}.json", std::process::id()));
+    KnownPeers::load(path.clone()).check(addr, "A").unwrap();
+    assert!(KnownPeers::load(path.clone()).check(addr, "B").is_err());
+    fs::remove_file(&path).unwrap();
+
+    let identity = Identity::generate().unwrap();
+    let loaded = Identity::from_pem(identity.pem.clone()).unwrap();
+    assert_eq!(loaded.fingerprint(), identity.fingerprint());
+    assert!(Identity::from_pem(String::new()).is_err());
+}
@@ -0,0 +1,297 @@
+//! How announcements travel over multicast. Every datagram starts with an
+//! envelope header: `MAGIC`, the format `VERSION` and the `Kind` of its body.
+//! Announcements too large for one datagram are split into fragments, and
+//! datagrams without a header are the JSON announcements of older peers.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use chrono::DateTime;
+use prost::Message;
+
+use crate::error::LandohError;
+use crate::pb;
+use crate::source::Source;
+
+pub(crate) const MAGIC: &[u8; 4] = b"LNDH";
+pub(crate) const VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 2;
+/// Message id, index and count of a fragment.
+const FRAGMENT_HEADER_LEN: usize = HEADER_LEN + 6;
+
+/// Datagrams stay below the minimum IPv6 MTU, so IP never fragments them.
+pub(crate) const MAX_DATAGRAM: usize = 1200;
+/// Large enough for any UDP datagram, including the unfragmented
+/// announcements of older peers.
+pub(crate) const RECEIVE_BUFFER: usize = 65536;
+const MAX_FRAGMENTS: usize = u8::MAX as usize;
+/// Announcements whose fragments do not all arrive within this are dropped.
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Incomplete announcements kept at once.
+const MAX_PENDING: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Kind {
+    /// A `pb::Announcement`.
+    Announcement = 1,
+    /// A datagram encrypted for a group.
+    Sealed = 2,
+    /// Part of a datagram too large to be sent at once.
+    Fragment = 3,
+}
+
+/// A received datagram with its envelope taken apart.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Datagram<'a> {
+    Announcement(&'a [u8]),
+    Sealed(&'a [u8]),
+    Fragment {
+        message: u32,
+        index: usize,
+        count: usize,
+        data: &'a [u8],
+    },
+    /// Sent by peers from before the envelope: JSON, or in a group the
+    /// encrypted JSON.
+    Legacy(&'a [u8]),
+}
+
+fn invalid(msg: &str) -> LandohError {
+    LandohError::Discovery(format!("rejected datagram: {}", msg))
+}
+
+/// Puts `body` into an envelope of `kind`.
+pub(crate) fn envelope(kind: Kind, body: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(HEADER_LEN + body.len());
+    datagram.extend_from_slice(MAGIC);
+    datagram.push(VERSION);
+    datagram.push(kind as u8);
+    datagram.extend_from_slice(body);
+    datagram
+}
+
+pub(crate) fn parse(datagram: &[u8]) -> Result<Datagram<'_>, LandohError> {
+    if !datagram.starts_with(MAGIC) {
+        return Ok(Datagram::Legacy(datagram));
+    }
+    if datagram.len() < HEADER_LEN {
+        return Err(invalid("truncated header"));
+    }
+    if datagram[MAGIC.len()] != VERSION {
+        return Err(invalid(&format!(
+            "unsupported version {}",
+            datagram[MAGIC.len()]
+        )));
+    }
+
+    let body = &datagram[HEADER_LEN..];
+    match datagram[MAGIC.len() + 1] {
+        k if k == Kind::Announcement as u8 => Ok(Datagram::Announcement(body)),
+        k if k == Kind::Sealed as u8 => Ok(Datagram::Sealed(body)),
+        k if k == Kind::Fragment as u8 => {
+            if datagram.len() < FRAGMENT_HEADER_LEN {
+                return Err(invalid("truncated fragment"));
+            }
+            let (index, count) = (body[4] as usize, body[5] as usize);
+            if index >= count {
+                return Err(invalid("fragment out of range"));
+            }
+            Ok(Datagram::Fragment {
+                message: u32::from_be_bytes([body[0], body[1], body[2], body[3]]),
+                index,
+                count,
+                data: &datagram[FRAGMENT_HEADER_LEN..],
+            })
+        }
+        k => Err(invalid(&format!("unknown kind {}", k))),
+    }
+}
+
+/// Splits `datagram` into fragments of `message` if it does not fit into
+/// one datagram.
+pub(crate) fn fragment(datagram: Vec<u8>, message: u32) -> Result<Vec<Vec<u8>>, LandohError> {
+    if datagram.len() <= MAX_DATAGRAM {
+        return Ok(vec![datagram]);
+    }
+
+    let chunks: Vec<&[u8]> = datagram
+        .chunks(MAX_DATAGRAM - FRAGMENT_HEADER_LEN)
+        .collect();
+    if chunks.len() > MAX_FRAGMENTS {
+        return Err(LandohError::Discovery(format!(
+            "announcement of {} bytes is too large to send",
+            datagram.len()
+        )));
+    }
+    Ok(chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut body = message.to_be_bytes().to_vec();
+            body.push(index as u8);
+            body.push(chunks.len() as u8);
+            body.extend_from_slice(chunk);
+            envelope(Kind::Fragment, &body)
+        })
+        .collect())
+}
+
+#[derive(Debug)]
+struct Pending {
+    started: Instant,
+    parts: Vec<Option<Vec<u8>>>,
+}
+
+/// Puts fragmented announcements back together, per sender.
+#[derive(Debug, Default)]
+pub(crate) struct Reassembler {
+    pending: HashMap<(SocketAddr, u32), Pending>,
+}
+
+impl Reassembler {
+    /// Returns the datagram `datagram` completes, the datagram itself if it
+    /// is not a fragment, or `None` while parts are missing.
+    pub(crate) fn push(
+        &mut self,
+        from: SocketAddr,
+        datagram: &[u8],
+    ) -> Result<Option<Vec<u8>>, LandohError> {
+        let (message, index, count, data) = match parse(datagram)? {
+            Datagram::Fragment {
+                message,
+                index,
+                count,
+                data,
+            } => (message, index, count, data),
+            _ => return Ok(Some(datagram.to_vec())),
+        };
+
+        self.pending
+            .retain(|_, p| p.started.elapsed() < FRAGMENT_TIMEOUT);
+        let key = (from, message);
+        if self.pending.len() >= MAX_PENDING && !self.pending.contains_key(&key) {
+            return Err(invalid("too many incomplete announcements"));
+        }
+        let pending = self.pending.entry(key).or_insert_with(|| Pending {
+            started: Instant::now(),
+            parts: vec![None; count],
+        });
+        if pending.parts.len() != count {
+            self.pending.remove(&key);
+            return Err(invalid("fragments disagree on their count"));
+        }
+        pending.parts[index] = Some(data.to_vec());
+
+        if pending.parts.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+        Ok(self
+            .pending
+            .remove(&key)
+            .map(|p| p.parts.into_iter().flatten().flatten().collect()))
+    }
+}
+
+pub(crate) fn encode_source(source: &Source) -> Vec<u8> {
+    pb::AnnouncedSource {
+        id: source.id.clone(),
+        nickname: source.nickname.clone(),
+        shared_directories: source.shared_directories.clone(),
+        seconds: source.timestamp.timestamp(),
+        nanos: source.timestamp.timestamp_subsec_nanos(),
+        fingerprint: source.fingerprint.clone(),
+        port: source.port.map(u32::from),
+        protocol: source.protocol,
+        leaving: source.leaving,
+    }
+    .encode_to_vec()
+}
+
+/// The address and interface are up to the receiver, they are not sent.
+pub(crate) fn decode_source(data: &[u8]) -> Result<Source, LandohError> {
+    let s = pb::AnnouncedSource::decode(data).map_err(|err| invalid(&err.to_string()))?;
+    let port = match s.port {
+        Some(p) => Some(u16::try_from(p).map_err(|_| invalid("port out of range"))?),
+        None => None,
+    };
+    Ok(Source {
+        timestamp: DateTime::from_timestamp(s.seconds, s.nanos)
+            .ok_or_else(|| invalid("timestamp out of range"))?,
+        port,
+        protocol: s.protocol,
+        fingerprint: s.fingerprint,
+        leaving: s.leaving,
+        ..Source::new(s.id, s.nickname, None, s.shared_directories)
+    })
+}
+
+#[test]
+fn test_envelope() {
+    let datagram = envelope(Kind::Announcement, b"body");
+    assert_eq!(&datagram[..4], MAGIC);
+    assert_eq!(parse(&datagram).unwrap(), Datagram::Announcement(b"body"));
+    assert_eq!(
+        parse(&envelope(Kind::Sealed, b"")).unwrap(),
+        Datagram::Sealed(b"")
+    );
+    assert_eq!(
+        parse(b"{\"a\": 1}").unwrap(),
+        Datagram::Legacy(b"{\"a\": 1}")
+    );
+
+    let mut newer = datagram.clone();
+    newer[4] = VERSION + 1;
+    assert!(parse(&newer).is_err());
+    let mut unknown = datagram;
+    unknown[5] = 9;
+    assert!(parse(&unknown).is_err());
+    assert!(parse(b"LNDH").is_err());
+    assert!(parse(&envelope(Kind::Fragment, &[0, 0, 0, 1, 2, 2])).is_err());
+
+    let mut source = Source::new(
+        "id".to_string(),
+        "nick".to_string(),
+        Some("10.0.0.1".to_string()),
+        vec!["a".to_string(), "b".to_string()],
+    )
+    .with_port(9100)
+    .with_fingerprint("fp".to_string());
+    let decoded = decode_source(&encode_source(&source)).unwrap();
+    assert_eq!(decoded.timestamp, source.timestamp);
+    assert_eq!(decoded.ip, None);
+    source.ip = None;
+    assert_eq!(
+        serde_json::to_value(&decoded).unwrap(),
+        serde_json::to_value(&source).unwrap()
+    );
+}
+
+#[test]
+fn test_fragments() {
+    let from: SocketAddr = "192.168.1.5:40000".parse().unwrap();
+    let mut fragments = Reassembler::default();
+
+    let small = envelope(Kind::Announcement, b"small");
+    assert_eq!(fragment(small.clone(), 1).unwrap(), vec![small.clone()]);
+    assert_eq!(fragments.push(from, &small).unwrap(), Some(small));
+
+    let large = envelope(Kind::Announcement, &vec![7u8; 3 * MAX_DATAGRAM]);
+    let parts = fragment(large.clone(), 2).unwrap();
+    assert_eq!(parts.len(), 4);
+    assert!(parts.iter().all(|p| p.len() <= MAX_DATAGRAM));
+
+    // In any order, repeated parts do no harm.
+    for p in [&parts[3], &parts[0], &parts[0], &parts[2]] {
+        assert_eq!(fragments.push(from, p).unwrap(), None);
+    }
+    // Parts of another sender do not mix in.
+    let other: SocketAddr = "192.168.1.6:40000".parse().unwrap();
+    assert_eq!(fragments.push(other, &parts[1]).unwrap(), None);
+    assert_eq!(fragments.push(from, &parts[1]).unwrap(), Some(large));
+
+    let huge = vec![0u8; (MAX_FRAGMENTS + 1) * MAX_DATAGRAM];
+    assert!(fragment(huge, 3).is_err());
+}
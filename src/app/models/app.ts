@@ -6,6 +6,60 @@ export class App {
     id: string
     nickname: string
     shared_directories: Directory[]
+    chunk_size: number
+    adaptive_chunk_size: boolean
+    compression: string
+    limits: RateLimits
+    transfers: TransferLimits
+    access: { [share: string]: Access }
+    // Passphrase of the private group this node is in, if any.
+    group?: string
+    ask_before_serving: boolean
+    remembered_peers: { [peer: string]: boolean }
+    // Network interfaces discovery runs on, by name; applied after a restart.
+    interfaces: { include: string[], exclude: string[] }
+    // Seconds between announcements, and until silent peers are dropped.
+    announce_interval: number
+    peer_ttl: number
+}
+
+export class ApprovalRequest {
+    id?: string
+    // Certificate fingerprint or address, answers refer to the peer by it.
+    peer: string
+    ip?: string
+    share: string
+}
+
+// A file a peer offers to send. Answers refer to it by peer, path, size and hash.
+export class FileOffer {
+    id?: string
+    peer: string
+    ip?: string
+    path: string
+    size: number
+    hash: string
+}
+
+export class Access {
+    // 'public', 'password' or 'restricted'
+    mode: string
+    // Set for 'password' when editing, the config only keeps a hash.
+    password?: string
+    peers?: string[]
+    networks?: string[]
+}
+
+export class TransferLimits {
+    global: number
+    per_peer: number
+    queue: number
+}
+
+export class RateLimits {
+    upload: number
+    peer_upload: number
+    download: number
 }
 
 export   enum Severity {
@@ -20,4 +74,5 @@ export   enum Severity {
     dir: string
     successful: string[]
     failed: string[]
+    corrupted: string[]
   }
\ No newline at end of file
@@ -12,7 +12,7 @@ import { appWindow } from '@tauri-apps/api/window';
 import { TreeNode } from 'primeng/api';
 
 import { Directory } from '../models/directory';
-import { App, Severity, FilePayload } from '../models/app';
+import { App, Access, ApprovalRequest, FileOffer, Severity, FilePayload, RateLimits } from '../models/app';
 
 @Component({
   selector: 'app-home',
@@ -27,6 +27,21 @@ export class AppHomeComponent implements OnInit {
     invoke('app_state').then((s) => {
       this.apps = [s as App];
       this.app = structuredClone(s as App);
+      this.limits = {
+        upload: this.app.limits.upload / 1024,
+        peer_upload: this.app.limits.peer_upload / 1024,
+        download: this.app.limits.download / 1024,
+      };
+      this.access = {};
+      this.app.shared_directories.forEach((d) => {
+        let a = this.app.access[d.name];
+        this.access[d.name] = {
+          mode: a ? a.mode : 'public',
+          password: '',
+          peers: a && a.peers ? [...a.peers] : [],
+          networks: a && a.networks ? [...a.networks] : [],
+        };
+      });
       console.log(this.app);
     })
   }
@@ -39,6 +54,15 @@ export class AppHomeComponent implements OnInit {
     invoke('update_nickname', {nickname: nick, window: appWindow}).then(() => this.app_state());
   }
 
+  update_limits(limits: RateLimits) {
+    invoke('update_limits', {
+      upload: limits.upload * 1024,
+      peerUpload: limits.peer_upload * 1024,
+      download: limits.download * 1024,
+      window: appWindow
+    }).then(() => this.app_state());
+  }
+
   async update_destination() {
     const selected = await open({
       multiple: false,
@@ -69,6 +93,86 @@ export class AppHomeComponent implements OnInit {
     }
   }
 
+  async add_shared_path(name: string) {
+    const selected = await open({
+      multiple: false,
+      directory: true,
+    })
+
+    if (selected) {
+      invoke('add_shared_dir', {path: selected, name: name, window: appWindow}).then(() => {
+        this.toast({severity:Severity.success,summary: 'Seeding "'+name+'" from',detail:selected.toString()})
+        this.app_state();
+      }).catch(() => this.toast({severity:Severity.error,summary: 'Failed to seed',detail: selected.toString()}));
+    }
+  }
+
+  async remove_shared_path(name: string, path: string) {
+    invoke('remove_shared_path', {name: name, path: path, window: appWindow}).then(() => {
+      this.toast({severity: Severity.success,summary: 'Unseeded',detail:path})
+      this.app_state();
+    }).catch(() => this.toast({severity: Severity.error, summary:'Failed to unseed',detail: path}));
+  }
+
+  update_ask_before_serving(enabled: boolean) {
+    invoke('update_ask_before_serving', {enabled: enabled}).then(() => this.app_state());
+  }
+
+  // Name of the peer asking for approval as far as it is known.
+  approval_peer(r: ApprovalRequest | FileOffer): string {
+    let source = this.sources ? this.sources.find((s) => s.id == r.id) : null;
+    if (source) {
+      return source.nickname;
+    }
+    return r.ip ? r.ip : r.peer;
+  }
+
+  async send_file(nickname: string, id: string) {
+    const selected = await open({
+      multiple: false,
+      directory: false,
+    })
+    if (!selected) {
+      return;
+    }
+    this.toast({severity: Severity.info, summary: 'Sending to "'+nickname+'"', detail: selected.toString()});
+    invoke('send_file', {id: id, path: selected}).then((stored) => {
+      this.toast({severity: Severity.success, summary: '"'+nickname+'" received', detail: stored as string})
+    }).catch((err) => this.toast({severity: Severity.error, summary: 'Failed to send to "'+nickname+'"', detail: err}));
+  }
+
+  answer_incoming_file(accept: boolean) {
+    let offer = this.incoming_files.shift();
+    invoke('answer_incoming_file', {offer: offer, accept: accept});
+  }
+
+  answer_approval(approve: boolean) {
+    let r = this.approvals.shift();
+    invoke('answer_approval', {peer: r.peer, approve: approve, remember: this.remember_peer}).then(() => {
+      if (this.remember_peer) {
+        this.app_state();
+      }
+      this.remember_peer = false;
+    });
+    // Further requests of the same peer are answered along with this one.
+    this.approvals = this.approvals.filter((a) => a.peer != r.peer);
+  }
+
+  set_share_access(name: string) {
+    let a = this.access[name];
+    invoke('set_share_access', {
+      name: name,
+      mode: a.mode,
+      password: a.password ? a.password : null,
+      peers: a.peers,
+      networks: a.networks,
+      window: appWindow
+    }).then(() => {
+      this.toast({severity: Severity.success, summary: 'Access of "'+name+'" set to', detail: a.mode})
+      this.app_state();
+    }).catch((err) => this.toast({severity: Severity.error, summary: 'Failed to set access of "'+name+'"', detail: err}));
+  }
+
   async remove_shared_dir(name: string) {
     invoke('remove_shared_dir', {path: name, window: appWindow}).then(() => {
       this.toast({severity: Severity.success,summary: 'Unseeded',detail:name})
@@ -86,7 +190,7 @@ export class AppHomeComponent implements OnInit {
 
   serve() {
     if (!this.serving) {
-      invoke('serve').then(() => this.toast({severity: Severity.info, summary:'Serving shared directories'})).catch(() => this.toast({severity: Severity.error, summary:'Error starting Server'}));
+      invoke('serve', {window: appWindow}).then(() => this.toast({severity: Severity.info, summary:'Serving shared directories'})).catch(() => this.toast({severity: Severity.error, summary:'Error starting Server'}));
       this.serving = true;
     }
   }
@@ -104,7 +208,16 @@ export class AppHomeComponent implements OnInit {
   }
 
   request_dir(id: string, name: string) {
-    invoke("request_dir", { id: id, dir: name, window: appWindow}).catch(() => this.toast({severity: Severity.error, summary:'Error requesting Directory: '+name + ' from ' + id}));
+    invoke("request_dir", { id: id, dir: name, password: this.passwords[name] || null, window: appWindow}).catch(() => this.toast({severity: Severity.error, summary:'Error requesting Directory: '+name + ' from ' + id}));
+  }
+
+  swarm_dir(name: string) {
+    this.toast({ severity: Severity.info, summary: 'Swarming', detail: '"'+name+'" from all seeds' });
+    invoke("swarm_dir", { dir: name, password: this.passwords[name] || null, window: appWindow}).catch(() => this.toast({severity: Severity.error, summary:'Error swarming Directory: '+name}));
+  }
+
+  seed_count(name: string): number {
+    return this.sources.filter((s) => s.name == name).length;
   }
 
  watch() {
@@ -175,6 +288,22 @@ confirm_request_dir(event: Event,nick: string, id: string, name: string) {
 
   apps: App[];
   app: App;
+  // Bandwidth limits in KiB/s as edited in the UI.
+  limits: RateLimits = {upload: 0, peer_upload: 0, download: 0};
+  // Access of each share as edited in the UI.
+  access: { [share: string]: Access } = {};
+  access_modes = [
+    {label: 'Public', value: 'public'},
+    {label: 'Password', value: 'password'},
+    {label: 'Restricted', value: 'restricted'},
+  ];
+  // Passwords for protected shares of other peers, by share name.
+  passwords: { [share: string]: string } = {};
+  // Peers waiting for approval, the one shown first.
+  approvals: ApprovalRequest[] = [];
+  // Files peers offer to send, the one shown first.
+  incoming_files: FileOffer[] = [];
+  remember_peer = false;
 
   dark: boolean;
 
@@ -211,10 +340,28 @@ confirm_request_dir(event: Event,nick: string, id: string, name: string) {
     
     // setInterval(async () => {
       // let ul = await 
+      await listen('approval', (event) => {
+        let r = event.payload as ApprovalRequest;
+        if (!this.approvals.some((a) => a.peer == r.peer)) {
+          this.approvals.push(r);
+        }
+      });
+
+      await listen('incoming_file', (event) => {
+        let o = event.payload as FileOffer;
+        if (!this.incoming_files.some((f) => f.peer == o.peer && f.path == o.path && f.size == o.size && f.hash == o.hash)) {
+          this.incoming_files.push(o);
+        }
+      });
+
       await listen('files', (event) => {
         let p =event.payload as FilePayload;
         this.filePayloads.push(p);
 
+        if(p.corrupted.length > 0) {
+          this.toast({severity: Severity.warn, summary: p.corrupted.length + ' file(s) of "'+p.dir+'" failed verification', detail: p.corrupted.join(', '), sticky: true})
+        }
+
         if(p.failed.length > 0) {
           if(p.successful.length < 1) {
             this.toast({severity: Severity.error,summary: 'Leech of "'+p.dir+'" failed', sticky: true})